@@ -0,0 +1,49 @@
+//! Compares HTTPCrypt encryption with ephemeral client keys (the default)
+//! and with a static client keypair and cached shared secrets.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rspamd_client::protocol::encryption::{
+    httpcrypt_encrypt, httpcrypt_encrypt_with_keypair, SharedSecretCache,
+};
+use rspamd_client::protocol::RspamdKeypair;
+use std::hint::black_box;
+
+const SERVER_PK: &str = "k4nz984k36xmcynm1hr9kdbn6jhcxf4ggbrb1quay7f88rpm9kay";
+const HEADERS: [(&str, &str); 2] = [("From", "user@example.com"), ("IP", "127.0.0.1")];
+
+fn bench_httpcrypt(c: &mut Criterion) {
+    let client_kp = RspamdKeypair::generate();
+    let cache = SharedSecretCache::default();
+    let mut group = c.benchmark_group("httpcrypt_encrypt");
+
+    for size in [1024usize, 64 * 1024] {
+        let body = vec![b'a'; size];
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("ephemeral", size), &body, |b, body| {
+            b.iter(|| {
+                httpcrypt_encrypt("/checkv2", black_box(body), HEADERS, SERVER_PK.as_bytes())
+                    .unwrap()
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("static_cached", size), &body, |b, body| {
+            b.iter(|| {
+                httpcrypt_encrypt_with_keypair(
+                    "/checkv2",
+                    black_box(body),
+                    HEADERS,
+                    SERVER_PK.as_bytes(),
+                    &client_kp,
+                    &cache,
+                )
+                .unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_httpcrypt);
+criterion_main!(benches);
@@ -1,17 +1,22 @@
+use crate::backend::cache::Lookup;
 use crate::backend::traits::*;
-use crate::config::{Config, EnvelopeData};
+use crate::backend::{
+    check_pong, decode_response, fallback_reply, parse_scan_reply, status_error, zstd_compress,
+};
+use crate::config::{Config, EnvelopeData, Priority};
 use crate::error::RspamdError;
 use crate::protocol::commands::{RspamdCommand, RspamdEndpoint};
-use crate::protocol::encryption::{httpcrypt_decrypt, httpcrypt_encrypt, make_key_header};
+use crate::protocol::encryption::{
+    httpcrypt_encrypt, httpcrypt_encrypt_with_keypair, make_key_header,
+};
 use crate::protocol::RspamdScanReply;
-use bytes::{Bytes, BytesMut};
+use crate::telemetry;
+use bytes::Bytes;
 use reqwest::header::{HeaderName, HeaderValue};
 use reqwest::Client;
 use std::collections::HashMap;
 use std::str::FromStr;
-use std::time::Duration;
-use url::Url;
-use zstd::zstd_safe::WriteBuf;
+use std::time::{Duration, Instant};
 
 pub struct AsyncClient<'a> {
     config: &'a Config,
@@ -20,7 +25,31 @@ pub struct AsyncClient<'a> {
 
 #[cfg(feature = "async")]
 pub fn async_client(options: &Config) -> Result<AsyncClient<'_>, RspamdError> {
-    let client = Client::builder().timeout(Duration::from_secs_f64(options.timeout));
+    options.validate()?;
+
+    let connection = &options.connection;
+    let mut client = Client::builder().timeout(options.timeout);
+    if let Some(timeout) = connection.connect_timeout {
+        client = client.connect_timeout(timeout);
+    }
+    if let Some(timeout) = connection.read_timeout {
+        client = client.read_timeout(timeout);
+    }
+    if let Some(timeout) = connection.pool_idle_timeout {
+        client = client.pool_idle_timeout(timeout);
+    }
+    if let Some(max) = connection.pool_max_idle_per_host {
+        client = client.pool_max_idle_per_host(max);
+    }
+    if let Some(interval) = connection.tcp_keepalive {
+        client = client.tcp_keepalive(interval);
+    }
+    if let Some(nodelay) = connection.tcp_nodelay {
+        client = client.tcp_nodelay(nodelay);
+    }
+    if connection.http2_prior_knowledge {
+        client = client.http2_prior_knowledge();
+    }
 
     let client = if let Some(ref proxy) = options.proxy_config {
         let proxy = reqwest::Proxy::all(proxy.proxy_url.clone())
@@ -53,6 +82,14 @@ pub fn async_client(options: &Config) -> Result<AsyncClient<'_>, RspamdError> {
     })
 }
 
+/// Errors of sending a request or reading the reply
+fn transport_error(e: reqwest::Error) -> RspamdError {
+    RspamdError::TransportError {
+        message: e.to_string(),
+        timeout: e.is_timeout(),
+    }
+}
+
 // Temporary structure for making a request
 pub struct ReqwestRequest<'a, B> {
     endpoint: RspamdEndpoint<'a>,
@@ -66,34 +103,121 @@ impl<'a, B: AsRef<[u8]> + Send> Request for ReqwestRequest<'a, B> {
     type Body = Bytes;
     type HeaderMap = reqwest::header::HeaderMap;
 
-    async fn response(mut self) -> Result<(Self::HeaderMap, Self::Body), RspamdError> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "rspamd_request",
+            skip_all,
+            fields(
+                command = self.endpoint.url,
+                upstream = %telemetry::redact_url(&self.client.config.base_url),
+                encrypted = self.client.config.encryption_key.is_some(),
+            )
+        )
+    )]
+    async fn response(self) -> Result<(Self::HeaderMap, Self::Body), RspamdError> {
+        let config = self.client.config;
+        let command = self.endpoint.url;
+        let result = self.execute_guarded().await;
+        if let Err(ref e) = result {
+            telemetry::request_failed(command, &config.base_url, e);
+        }
+        result
+    }
+}
+
+#[maybe_async::maybe_async]
+impl<'a, B: AsRef<[u8]> + Send> ReqwestRequest<'a, B> {
+    pub async fn new(
+        client: AsyncClient<'a>,
+        body: B,
+        command: RspamdCommand,
+        envelope_data: EnvelopeData,
+    ) -> Result<ReqwestRequest<'a, B>, RspamdError> {
+        Ok(Self {
+            endpoint: RspamdEndpoint::from_command(command),
+            client,
+            body,
+            envelope_data: Some(envelope_data),
+        })
+    }
+
+    /// Create a request to an endpoint, e.g. one with a query string
+    pub fn with_endpoint(
+        client: AsyncClient<'a>,
+        body: B,
+        endpoint: RspamdEndpoint<'a>,
+        envelope_data: EnvelopeData,
+    ) -> ReqwestRequest<'a, B> {
+        Self {
+            endpoint,
+            client,
+            body,
+            envelope_data: Some(envelope_data),
+        }
+    }
+
+    /// Send the request within the limits of the rate limiter and the circuit breaker
+    async fn execute_guarded(self) -> Result<(reqwest::header::HeaderMap, Bytes), RspamdError> {
+        let config = self.client.config;
+        let priority = self
+            .envelope_data
+            .as_ref()
+            .map_or(Priority::default(), |e| e.priority);
+        let _slot = match config.rate_limiter {
+            Some(ref limiter) => Some(limiter.acquire(priority).await?),
+            None => None,
+        };
+        match config.circuit_breaker {
+            Some(ref breaker) => {
+                let permit = breaker.acquire(&config.base_url)?;
+                let result = self.execute().await;
+                permit.record(&result);
+                result
+            }
+            None => self.execute().await,
+        }
+    }
+
+    /// Send the request, retrying on transport errors, and decode the response
+    async fn execute(mut self) -> Result<(reqwest::header::HeaderMap, Bytes), RspamdError> {
         let mut retry_cnt = self.client.config.retries;
+        let mut attempt = 0;
         let mut maybe_sk = Default::default();
         let extra_hdrs: HashMap<String, String> =
             HashMap::from_iter(self.envelope_data.take().unwrap());
 
-        let response = loop {
+        let (response, started) = loop {
+            attempt += 1;
             // Check if File header is present - if so, we don't need to send the body
             let has_file_header = extra_hdrs.contains_key("File");
             let need_body = self.endpoint.need_body && !has_file_header;
+            let compression = &self.client.config.compression;
+            let compress = self.client.config.zstd
+                && need_body
+                && self.endpoint.compressible()
+                && self.body.as_ref().len() >= compression.min_size;
             let method = if need_body {
                 reqwest::Method::POST
             } else {
                 reqwest::Method::GET
             };
 
-            let mut url = Url::from_str(self.client.config.base_url.as_str())
-                .map_err(|e| RspamdError::HttpError(e.to_string()))?;
+            let (mut url, password) = self.client.config.server_url()?;
             url.set_path(self.endpoint.url);
-            let mut req = self.client.inner.request(method, url.clone());
+            url.set_query(self.endpoint.query.as_deref());
+            let mut req = self.client.inner.request(method.clone(), url.clone());
 
-            if let Some(ref password) = self.client.config.password {
+            if let Some(ref password) = password {
                 req = req.header("Password", password);
             }
 
-            if self.client.config.zstd && need_body {
+            if compress {
                 req = req.header("Content-Encoding", "zstd");
                 req = req.header("Compression", "zstd");
+                if let Some(ref dictionary) = compression.dictionary {
+                    req = req.header("Dictionary", dictionary.id().to_string());
+                }
             }
 
             for (k, v) in extra_hdrs.iter() {
@@ -105,20 +229,37 @@ impl<'a, B: AsRef<[u8]> + Send> Request for ReqwestRequest<'a, B> {
                     .build()
                     .map_err(|e| RspamdError::HttpError(e.to_string()))?;
                 let body = if need_body {
-                    if self.client.config.zstd {
-                        zstd::encode_all(self.body.as_ref(), 0)?
+                    if compress {
+                        zstd_compress(self.body.as_ref(), compression)?
                     } else {
                         self.body.as_ref().to_vec()
                     }
                 } else {
                     Vec::new()
                 };
-                let encrypted = httpcrypt_encrypt(
-                    url.path(),
-                    body.as_slice(),
-                    inner_req.headers(),
-                    encryption_key.as_bytes(),
-                )?;
+                let encrypt_started = Instant::now();
+                let encrypted = if let Some(ref keypair) = self.client.config.client_keypair {
+                    httpcrypt_encrypt_with_keypair(
+                        &url[url::Position::BeforePath..],
+                        body.as_slice(),
+                        inner_req.headers(),
+                        encryption_key.as_bytes(),
+                        keypair,
+                        &self.client.config.shared_secrets,
+                    )?
+                } else {
+                    httpcrypt_encrypt(
+                        &url[url::Position::BeforePath..],
+                        body.as_slice(),
+                        inner_req.headers(),
+                        encryption_key.as_bytes(),
+                    )?
+                };
+                telemetry::encrypted(
+                    encrypted.body.len(),
+                    self.client.config.client_keypair.is_some(),
+                    encrypt_started.elapsed(),
+                );
                 req = self.client.inner.request(reqwest::Method::POST, url);
                 let key_header =
                     make_key_header(encryption_key.as_str(), encrypted.peer_key.as_str())?;
@@ -126,93 +267,75 @@ impl<'a, B: AsRef<[u8]> + Send> Request for ReqwestRequest<'a, B> {
                 req = req.body(encrypted.body);
                 maybe_sk = Some(encrypted.shared_key);
             } else if need_body {
-                req = if self.client.config.zstd {
-                    req.body(reqwest::Body::from(zstd::encode_all(
+                req = if compress {
+                    req.body(reqwest::Body::from(zstd_compress(
                         self.body.as_ref(),
-                        0,
+                        compression,
                     )?))
                 } else {
                     req.body(Bytes::copy_from_slice(self.body.as_ref()))
                 };
             }
 
-            let req = req.timeout(Duration::from_secs_f64(self.client.config.timeout));
+            let req = req.timeout(self.client.config.timeout);
             let req = req
                 .build()
                 .map_err(|e| RspamdError::HttpError(e.to_string()))?;
 
+            telemetry::attempt_started(
+                attempt,
+                req.method().as_str(),
+                req.body().and_then(|b| b.as_bytes()).map_or(0, |b| b.len()),
+            );
+            let started = Instant::now();
             match self.client.inner.execute(req).await {
-                Ok(v) => break Ok(v),
+                Ok(v) => break Ok((v, started)),
                 Err(e) => {
-                    if (retry_cnt - 1) == 0 {
+                    let will_retry = retry_cnt > 1;
+                    telemetry::attempt_failed(
+                        self.endpoint.url,
+                        attempt,
+                        &e,
+                        started.elapsed(),
+                        will_retry,
+                    );
+                    if !will_retry {
                         break Err(e);
                     }
                     retry_cnt -= 1;
-                    let delay = Duration::from_secs_f64(self.client.config.timeout);
+                    let delay = self.client.config.timeout;
                     tokio::time::sleep(delay).await;
                     continue;
                 }
             };
         }
-        .map_err(|e| RspamdError::HttpError(e.to_string()))?;
+        .map_err(transport_error)?;
 
+        telemetry::response_received(
+            self.endpoint.url,
+            &self.client.config.base_url,
+            attempt,
+            response.status().as_u16(),
+            started.elapsed(),
+        );
         if !response.status().is_success() {
-            return Err(RspamdError::HttpError(format!(
-                "Status: {}",
-                response.status()
-            )));
+            let status = response.status().as_u16();
+            let body = response.bytes().await.unwrap_or_default();
+            return Err(status_error(&self.endpoint, status, &body));
         }
 
-        if let Some(sk) = maybe_sk {
-            let mut body = BytesMut::from(
-                response
-                    .bytes()
-                    .await
-                    .map_err(|e| RspamdError::HttpError(e.to_string()))?,
-            );
-            let decrypted_offset = httpcrypt_decrypt(body.as_mut(), sk)?;
-            let mut hdrs = [httparse::EMPTY_HEADER; 64];
-            let mut parsed = httparse::Response::new(&mut hdrs);
-
-            let body_offset = parsed
-                .parse(&body.as_slice()[decrypted_offset..])
-                .map_err(|s| RspamdError::HttpError(s.to_string()))?;
-            let mut output_hdrs = reqwest::header::HeaderMap::with_capacity(parsed.headers.len());
-            for hdr in parsed.headers.iter_mut() {
-                output_hdrs.insert(
-                    HeaderName::from_str(hdr.name)?,
-                    HeaderValue::from_str(std::str::from_utf8(hdr.value)?)?,
-                );
-            }
-            let body = if output_hdrs
-                .get("Compression")
-                .is_some_and(|hv| hv == "zstd")
-            {
-                zstd::decode_all(&body.as_slice()[body_offset.unwrap() + decrypted_offset..])?
-            } else {
-                body.as_slice()[body_offset.unwrap() + decrypted_offset..].to_vec()
-            };
-            Ok((output_hdrs, body.into()))
-        } else {
-            Ok((response.headers().clone(), response.bytes().await?))
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+            .collect();
+        let body = response.bytes().await.map_err(transport_error)?;
+        let (headers, body) = decode_response(headers, body.into(), maybe_sk)?;
+        let mut output_hdrs = reqwest::header::HeaderMap::with_capacity(headers.len());
+        for (name, value) in headers {
+            output_hdrs.insert(HeaderName::from_str(&name)?, HeaderValue::from_str(&value)?);
         }
-    }
-}
-
-#[maybe_async::maybe_async]
-impl<'a, B: AsRef<[u8]> + Send> ReqwestRequest<'a, B> {
-    pub async fn new(
-        client: AsyncClient<'a>,
-        body: B,
-        command: RspamdCommand,
-        envelope_data: EnvelopeData,
-    ) -> Result<ReqwestRequest<'a, B>, RspamdError> {
-        Ok(Self {
-            endpoint: RspamdEndpoint::from_command(command),
-            client,
-            body,
-            envelope_data: Some(envelope_data),
-        })
+        Ok((output_hdrs, body.into()))
     }
 }
 
@@ -237,42 +360,81 @@ impl<'a, B: AsRef<[u8]> + Send> ReqwestRequest<'a, B> {
 /// }
 /// ```
 #[maybe_async::maybe_async]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "rspamd_scan", skip_all, fields(body_size = body.as_ref().len()))
+)]
 pub async fn scan_async<B: AsRef<[u8]> + Send>(
     options: &Config,
     body: B,
     envelope_data: EnvelopeData,
 ) -> Result<RspamdScanReply, RspamdError> {
+    let cache_key = match options
+        .cache
+        .as_ref()
+        .map(|cache| cache.lookup(&options.base_url, body.as_ref(), &envelope_data))
+    {
+        Some(Lookup::Hit(reply)) => return Ok(*reply),
+        Some(Lookup::Miss(key)) => Some(key),
+        _ => None,
+    };
     let client = async_client(options)?;
     let request = ReqwestRequest::new(client, body, RspamdCommand::Scan, envelope_data).await?;
-    let (headers, body) = request
-        .response()
-        .await
-        .map_err(|e| RspamdError::HttpError(e.to_string()))?;
+    let command = request.endpoint.url;
+    let (headers, body) = match request.response().await {
+        Ok(reply) => reply,
+        Err(e) => return fallback_reply(options, e),
+    };
 
-    // Check for Message-Offset header to handle body_block feature
-    let response = if let Some(offset_header) = headers.get("Message-Offset") {
-        let offset = offset_header
-            .to_str()
-            .map_err(|e| RspamdError::HttpError(format!("Invalid Message-Offset header: {}", e)))?
-            .parse::<usize>()
-            .map_err(|e| RspamdError::HttpError(format!("Invalid Message-Offset value: {}", e)))?;
+    let response = parse_scan_reply(
+        options,
+        command,
+        headers.get("Message-Offset").map(|v| v.as_bytes()),
+        &body,
+    )?;
+    if let (Some(cache), Some(key)) = (options.cache.as_ref(), cache_key) {
+        cache.insert(key, &response);
+    }
 
-        if offset < body.len() {
-            // Split body into JSON part and rewritten body part
-            let json_part = &body[..offset];
-            let body_part = &body[offset..];
+    Ok(response)
+}
 
-            let mut response = serde_json::from_slice::<RspamdScanReply>(json_part)?;
-            response.rewritten_body = Some(body_part.to_vec());
-            response
-        } else {
-            // Offset is out of bounds, parse entire body as JSON
-            serde_json::from_slice::<RspamdScanReply>(body.as_ref())?
-        }
-    } else {
-        // No Message-Offset header, parse entire body as JSON
-        serde_json::from_slice::<RspamdScanReply>(body.as_ref())?
-    };
+/// Send a request to an endpoint, returning the decoded reply body
+#[maybe_async::maybe_async]
+pub(crate) async fn request_async<B: AsRef<[u8]> + Send>(
+    options: &Config,
+    endpoint: RspamdEndpoint<'_>,
+    body: B,
+    envelope_data: EnvelopeData,
+) -> Result<Bytes, RspamdError> {
+    let client = async_client(options)?;
+    let request = ReqwestRequest::with_endpoint(client, body, endpoint, envelope_data);
+    let (_, body) = request.response().await?;
+    Ok(body)
+}
 
-    Ok(response)
+/// Check that the server is alive with `/ping`, returning the round-trip time.
+/// Example:
+/// ```rust,no_run
+/// use rspamd_client::config::Config;
+/// use rspamd_client::ping_async;
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), rspamd_client::error::RspamdError> {
+/// let config = Config::builder()
+///     .base_url("http://localhost:11333".to_string())
+///     .build();
+/// let rtt = ping_async(&config).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[maybe_async::maybe_async]
+pub async fn ping_async(options: &Config) -> Result<Duration, RspamdError> {
+    let started = Instant::now();
+    let client = async_client(options)?;
+    let request =
+        ReqwestRequest::new(client, &[][..], RspamdCommand::Ping, Default::default()).await?;
+    let (_, body) = request.response().await?;
+    check_pong(&body)?;
+    Ok(started.elapsed())
 }
@@ -0,0 +1,410 @@
+//! Client-side cache of scan replies, for messages scanned several times (e.g. before queueing
+//! and at delivery, or once per recipient).
+//!
+//! Replies are keyed by a digest of the server URL, the message body and the envelope: sender,
+//! recipients, IP, user, HELO, hostname, flags and additional headers (which carry settings),
+//! so a scan sent to another server, or with a different envelope or settings, is never served
+//! a cached reply: configurations copied with `Config::with_base_url` can share a cache. Entries
+//! expire after `ttl`, and least recently used ones are evicted above `capacity`. Fallback
+//! verdicts and local file scans (`EnvelopeData::file_path`) are not cached.
+
+use crate::config::{CacheSettings, EnvelopeData};
+use crate::protocol::RspamdScanReply;
+use crate::telemetry;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+type Key = [u8; 32];
+
+/// Counters of a scan cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// Scans served from the cache
+    pub hits: u64,
+    /// Scans not found in the cache, or expired
+    pub misses: u64,
+    /// Scans that have bypassed the cache, see `EnvelopeData::bypass_cache`
+    pub bypassed: u64,
+    /// Replies evicted to keep the cache within its capacity
+    pub evictions: u64,
+    /// Replies currently cached
+    pub entries: usize,
+}
+
+/// Result of a cache lookup
+pub(crate) enum Lookup {
+    /// Cached reply
+    Hit(Box<RspamdScanReply>),
+    /// The reply should be stored with this key
+    Miss(Key),
+    /// The scan cannot be cached
+    Skip,
+}
+
+struct Entry {
+    reply: RspamdScanReply,
+    stored_at: Instant,
+    used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Key, Entry>,
+    /// Keys by last use
+    lru: BTreeMap<u64, Key>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl Inner {
+    fn touch(&mut self, key: &Key) -> u64 {
+        self.tick += 1;
+        self.lru.insert(self.tick, *key);
+        self.tick
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.lru.remove(&entry.used);
+        Some(entry)
+    }
+}
+
+/// Cache of scan replies, clones share the same entries and counters
+#[derive(Clone)]
+pub struct ScanCache {
+    settings: CacheSettings,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl ScanCache {
+    pub fn new(settings: CacheSettings) -> Self {
+        Self {
+            settings,
+            inner: Default::default(),
+        }
+    }
+
+    pub fn settings(&self) -> &CacheSettings {
+        &self.settings
+    }
+
+    /// Hit and miss counters
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner();
+        CacheStats {
+            entries: inner.entries.len(),
+            ..inner.stats
+        }
+    }
+
+    /// Remove all cached replies, counters are kept
+    pub fn clear(&self) {
+        let mut inner = self.inner();
+        inner.entries.clear();
+        inner.lru.clear();
+    }
+
+    fn inner(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Digest of the server URL, the body and the envelope fields sent to the server
+    fn key(base_url: &str, body: &[u8], envelope: &EnvelopeData) -> Key {
+        let mut state = blake2b_simd::Params::new().hash_length(32).to_state();
+        let mut field = |value: &[u8]| {
+            state.update(&(value.len() as u64).to_le_bytes());
+            state.update(value);
+        };
+        field(base_url.as_bytes());
+        field(body);
+        for value in [
+            &envelope.from,
+            &envelope.ip,
+            &envelope.user,
+            &envelope.helo,
+            &envelope.hostname,
+        ] {
+            field(value.as_deref().unwrap_or_default().as_bytes());
+        }
+        field(&[envelope.body_block as u8]);
+        field(&(envelope.rcpt.len() as u64).to_le_bytes());
+        for rcpt in &envelope.rcpt {
+            field(rcpt.as_bytes());
+        }
+        let mut headers = envelope.additional_headers.iter().collect::<Vec<_>>();
+        headers.sort();
+        for (name, value) in headers {
+            field(name.as_bytes());
+            field(value.as_bytes());
+        }
+        let mut key = Key::default();
+        key.copy_from_slice(state.finalize().as_bytes());
+        key
+    }
+
+    /// Look up the reply of a scan
+    pub(crate) fn lookup(&self, base_url: &str, body: &[u8], envelope: &EnvelopeData) -> Lookup {
+        if envelope.file_path.is_some() {
+            return Lookup::Skip;
+        }
+        let key = Self::key(base_url, body, envelope);
+        let mut inner = self.inner();
+        if envelope.bypass_cache {
+            inner.stats.bypassed += 1;
+            telemetry::cache_lookup("bypass");
+            return Lookup::Miss(key);
+        }
+        let expired = match inner.entries.get(&key) {
+            Some(entry) => entry.stored_at.elapsed() >= self.settings.ttl,
+            None => {
+                inner.stats.misses += 1;
+                telemetry::cache_lookup("miss");
+                return Lookup::Miss(key);
+            }
+        };
+        if expired {
+            inner.remove(&key);
+            inner.stats.misses += 1;
+            telemetry::cache_lookup("miss");
+            return Lookup::Miss(key);
+        }
+        let used = inner.entries[&key].used;
+        inner.lru.remove(&used);
+        let used = inner.touch(&key);
+        inner.stats.hits += 1;
+        telemetry::cache_lookup("hit");
+        let entry = inner.entries.get_mut(&key).expect("entry exists");
+        entry.used = used;
+        Lookup::Hit(Box::new(entry.reply.clone()))
+    }
+
+    /// Store the reply of a scan, evicting least recently used replies if the cache is full
+    pub(crate) fn insert(&self, key: Key, reply: &RspamdScanReply) {
+        if reply.is_fallback() {
+            return;
+        }
+        let mut inner = self.inner();
+        inner.remove(&key);
+        while inner.entries.len() >= self.settings.capacity.max(1) {
+            let Some((_, oldest)) = inner.lru.pop_first() else {
+                break;
+            };
+            inner.entries.remove(&oldest);
+            inner.stats.evictions += 1;
+        }
+        let used = inner.touch(&key);
+        inner.entries.insert(
+            key,
+            Entry {
+                reply: reply.clone(),
+                stored_at: Instant::now(),
+                used,
+            },
+        );
+    }
+}
+
+impl fmt::Debug for ScanCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScanCache")
+            .field("settings", &self.settings)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+/// Entries are not a part of the configuration, caches with the same settings are equal
+impl PartialEq for ScanCache {
+    fn eq(&self, other: &Self) -> bool {
+        self.settings == other.settings
+    }
+}
+
+impl<'de> Deserialize<'de> for ScanCache {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        CacheSettings::deserialize(deserializer).map(ScanCache::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[cfg(feature = "async")]
+    use crate::scan_async as scan;
+    #[cfg(feature = "sync")]
+    use crate::scan_sync as scan;
+
+    const UPSTREAM: &str = "http://localhost:11333";
+
+    fn reply(action: &str) -> RspamdScanReply {
+        serde_json::from_str(&format!(r#"{{"action":"{}","score":1.0}}"#, action)).unwrap()
+    }
+
+    fn miss_key(lookup: Lookup) -> Key {
+        match lookup {
+            Lookup::Miss(key) => key,
+            _ => panic!("expected a cache miss"),
+        }
+    }
+
+    fn hit(lookup: Lookup) -> Option<String> {
+        match lookup {
+            Lookup::Hit(reply) => Some(reply.action),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_keys() {
+        let envelope = EnvelopeData::builder()
+            .from("a@example.com".to_string())
+            .rcpt(vec!["b@example.com".to_string()])
+            .additional_headers(HashMap::from([
+                ("Settings-ID".to_string(), "inbound".to_string()),
+                ("Queue-ID".to_string(), "1".to_string()),
+            ]))
+            .build();
+        let key = ScanCache::key(UPSTREAM, b"Test", &envelope);
+        assert_eq!(key, ScanCache::key(UPSTREAM, b"Test", &envelope.clone()));
+        assert_ne!(key, ScanCache::key(UPSTREAM, b"Test2", &envelope));
+        assert_ne!(
+            key,
+            ScanCache::key("http://localhost:11334", b"Test", &envelope)
+        );
+
+        let mut other = envelope.clone();
+        other.rcpt.push("c@example.com".to_string());
+        assert_ne!(key, ScanCache::key(UPSTREAM, b"Test", &other));
+        let mut other = envelope.clone();
+        other
+            .additional_headers
+            .insert("Settings-ID".to_string(), "outbound".to_string());
+        assert_ne!(key, ScanCache::key(UPSTREAM, b"Test", &other));
+        // Client-side options do not change the key
+        let mut other = envelope.clone();
+        other.priority = crate::config::Priority::Batch;
+        other.bypass_cache = true;
+        assert_eq!(key, ScanCache::key(UPSTREAM, b"Test", &other));
+    }
+
+    #[test]
+    fn test_lru_and_ttl() {
+        let cache = ScanCache::new(
+            CacheSettings::builder()
+                .capacity(2)
+                .ttl(Duration::from_millis(50))
+                .build(),
+        );
+        let envelope = EnvelopeData::default();
+        let a = miss_key(cache.lookup(UPSTREAM, b"a", &envelope));
+        let b = miss_key(cache.lookup(UPSTREAM, b"b", &envelope));
+        let c = miss_key(cache.lookup(UPSTREAM, b"c", &envelope));
+        cache.insert(a, &reply("no action"));
+        cache.insert(b, &reply("reject"));
+        // `a` becomes the most recently used, so `b` is evicted
+        assert_eq!(
+            hit(cache.lookup(UPSTREAM, b"a", &envelope)).as_deref(),
+            Some("no action")
+        );
+        cache.insert(c, &reply("greylist"));
+        assert!(hit(cache.lookup(UPSTREAM, b"b", &envelope)).is_none());
+        assert_eq!(
+            hit(cache.lookup(UPSTREAM, b"c", &envelope)).as_deref(),
+            Some("greylist")
+        );
+        cache.insert(
+            a,
+            &RspamdScanReply::fallback("reject", "timeout".to_string()),
+        );
+        assert_eq!(
+            hit(cache.lookup(UPSTREAM, b"a", &envelope)).as_deref(),
+            Some("no action")
+        );
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(hit(cache.lookup(UPSTREAM, b"a", &envelope)).is_none());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 5,
+                bypassed: 0,
+                evictions: 1,
+                entries: 1,
+            }
+        );
+        let file = EnvelopeData::builder()
+            .file_path("/tmp/message.eml".to_string())
+            .build();
+        assert!(matches!(cache.lookup(UPSTREAM, b"", &file), Lookup::Skip));
+    }
+
+    /// Serve a single scan request, further connections are refused
+    fn serve_once() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let mut hdrs = [httparse::EMPTY_HEADER; 64];
+                let mut parsed = httparse::Request::new(&mut hdrs);
+                if let httparse::Status::Complete(offset) = parsed.parse(&request).unwrap() {
+                    let length = parsed
+                        .headers
+                        .iter()
+                        .find(|h| h.name.eq_ignore_ascii_case("Content-Length"))
+                        .map_or(0, |h| {
+                            std::str::from_utf8(h.value).unwrap().parse().unwrap()
+                        });
+                    if request.len() >= offset + length {
+                        break;
+                    }
+                }
+            }
+            let body = r#"{"action":"reject","score":15.5}"#;
+            let reply = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(reply.as_bytes()).unwrap();
+        });
+        base_url
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_cached_scans() {
+        let cache = ScanCache::new(CacheSettings::default());
+        let config = Config::builder()
+            .base_url(serve_once())
+            .zstd(false)
+            .retries(1)
+            .cache(cache.clone())
+            .build();
+
+        let reply = scan(&config, "Test", Default::default()).await.unwrap();
+        assert_eq!(reply.action, "reject");
+        // The server is gone, the reply comes from the cache
+        let reply = scan(&config, "Test", Default::default()).await.unwrap();
+        assert_eq!(reply.action, "reject");
+
+        let envelope = EnvelopeData::builder().bypass_cache(true).build();
+        let result = scan(&config, "Test", envelope).await;
+        assert!(result.is_err());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.bypassed), (1, 1, 1));
+    }
+}
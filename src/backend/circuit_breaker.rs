@@ -0,0 +1,412 @@
+//! Circuit breaker tracking each upstream from the outcome of requests.
+//!
+//! While the circuit of an upstream is closed, requests are sent as usual and their outcomes
+//! are recorded in a sliding window. When the rate of failed or slow requests in the window
+//! reaches its threshold, the circuit opens: requests fail immediately with
+//! `RspamdError::CircuitOpen` instead of waiting for timeouts and retries. After
+//! `open_duration` the circuit is half-open and a few probe requests are let through; it closes
+//! when they all succeed and opens again on the first failure.
+//!
+//! Failures are transport errors, timeouts and server errors with a 5xx status. Other errors,
+//! such as authorization failures, show that the server is alive and count as successes.
+
+use crate::config::CircuitBreakerSettings;
+use crate::error::RspamdError;
+use crate::telemetry;
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// State of the circuit of an upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests are sent
+    Closed,
+    /// Requests fail immediately
+    Open,
+    /// A limited number of probe requests are sent
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// Name of the state, suitable for logs and metric labels
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Outcome of a request in the window
+#[derive(Clone, Copy)]
+struct Outcome {
+    failed: bool,
+    slow: bool,
+}
+
+struct Circuit {
+    state: CircuitState,
+    window: VecDeque<Outcome>,
+    opened_at: Instant,
+    /// Probe requests in flight while half-open
+    probes: u32,
+    /// Successful probe requests while half-open
+    successes: u32,
+}
+
+impl Circuit {
+    fn new() -> Self {
+        Self {
+            state: CircuitState::Closed,
+            window: VecDeque::new(),
+            opened_at: Instant::now(),
+            probes: 0,
+            successes: 0,
+        }
+    }
+
+    /// Current state, an open circuit becomes half-open after `open_duration`
+    fn state(&self, open_duration: Duration) -> CircuitState {
+        match self.state {
+            CircuitState::Open if self.opened_at.elapsed() >= open_duration => {
+                CircuitState::HalfOpen
+            }
+            state => state,
+        }
+    }
+
+    fn transition(&mut self, upstream: &str, state: CircuitState) {
+        if self.state != state {
+            telemetry::circuit_state_changed(upstream, state);
+        }
+        self.state = state;
+        self.window.clear();
+        self.probes = 0;
+        self.successes = 0;
+        if state == CircuitState::Open {
+            self.opened_at = Instant::now();
+        }
+    }
+}
+
+/// Circuit breaker with a circuit per upstream, keyed by the base URL without credentials.
+/// Clones share the same circuits.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    settings: CircuitBreakerSettings,
+    circuits: Arc<Mutex<HashMap<String, Circuit>>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(settings: CircuitBreakerSettings) -> Self {
+        Self {
+            settings,
+            circuits: Default::default(),
+        }
+    }
+
+    pub fn settings(&self) -> &CircuitBreakerSettings {
+        &self.settings
+    }
+
+    /// State of the circuit of an upstream, given by its base URL
+    pub fn state(&self, base_url: &str) -> CircuitState {
+        self.circuits()
+            .get(&telemetry::redact_url(base_url))
+            .map_or(CircuitState::Closed, |c| {
+                c.state(self.settings.open_duration)
+            })
+    }
+
+    /// States of all upstreams that have been requested, sorted by upstream
+    pub fn states(&self) -> Vec<(String, CircuitState)> {
+        let mut states: Vec<_> = self
+            .circuits()
+            .iter()
+            .map(|(upstream, c)| (upstream.clone(), c.state(self.settings.open_duration)))
+            .collect();
+        states.sort_by(|a, b| a.0.cmp(&b.0));
+        states
+    }
+
+    /// Close the circuit of an upstream, e.g. after it has been fixed manually
+    pub fn reset(&self, base_url: &str) {
+        let upstream = telemetry::redact_url(base_url);
+        if let Some(circuit) = self.circuits().get_mut(&upstream) {
+            circuit.transition(&upstream, CircuitState::Closed);
+        }
+    }
+
+    fn circuits(&self) -> MutexGuard<'_, HashMap<String, Circuit>> {
+        self.circuits.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Allow a request to the upstream or fail fast if its circuit is open
+    pub(crate) fn acquire(&self, base_url: &str) -> Result<Permit<'_>, RspamdError> {
+        let upstream = telemetry::redact_url(base_url);
+        let mut circuits = self.circuits();
+        let circuit = circuits
+            .entry(upstream.clone())
+            .or_insert_with(Circuit::new);
+        let probe = match circuit.state(self.settings.open_duration) {
+            CircuitState::Closed => false,
+            CircuitState::HalfOpen if circuit.probes < self.settings.half_open_requests => {
+                if circuit.state == CircuitState::Open {
+                    circuit.transition(&upstream, CircuitState::HalfOpen);
+                }
+                circuit.probes += 1;
+                true
+            }
+            _ => return Err(RspamdError::CircuitOpen(upstream)),
+        };
+        drop(circuits);
+        Ok(Permit {
+            breaker: self,
+            upstream,
+            probe,
+            started: Instant::now(),
+        })
+    }
+
+    fn record(&self, upstream: &str, probe: bool, outcome: Outcome) {
+        let settings = &self.settings;
+        let mut circuits = self.circuits();
+        let Some(circuit) = circuits.get_mut(upstream) else {
+            return;
+        };
+        match circuit.state {
+            CircuitState::Closed if !probe => {
+                circuit.window.push_back(outcome);
+                if circuit.window.len() > settings.window_size {
+                    circuit.window.pop_front();
+                }
+                let total = circuit.window.len();
+                if total < settings.minimum_requests.max(1) {
+                    return;
+                }
+                let rate = |count: usize| count as f64 / total as f64;
+                let failed = rate(circuit.window.iter().filter(|o| o.failed).count());
+                let slow = rate(circuit.window.iter().filter(|o| o.slow).count());
+                if failed >= settings.failure_rate_threshold
+                    || (settings.slow_request_duration.is_some()
+                        && slow >= settings.slow_request_rate_threshold)
+                {
+                    circuit.transition(upstream, CircuitState::Open);
+                }
+            }
+            CircuitState::HalfOpen if probe => {
+                circuit.probes = circuit.probes.saturating_sub(1);
+                if outcome.failed || outcome.slow {
+                    circuit.transition(upstream, CircuitState::Open);
+                } else {
+                    circuit.successes += 1;
+                    if circuit.successes >= settings.half_open_requests {
+                        circuit.transition(upstream, CircuitState::Closed);
+                    }
+                }
+            }
+            // Outcomes of requests started in another state are ignored
+            _ => {}
+        }
+    }
+
+    /// Release a probe that has not completed, e.g. a cancelled request
+    fn release(&self, upstream: &str) {
+        if let Some(circuit) = self.circuits().get_mut(upstream) {
+            if circuit.state == CircuitState::HalfOpen {
+                circuit.probes = circuit.probes.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// Request allowed by the circuit breaker, its outcome must be recorded with `Permit::record`
+pub(crate) struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    upstream: String,
+    probe: bool,
+    started: Instant,
+}
+
+impl Permit<'_> {
+    /// Record the outcome of the request
+    pub(crate) fn record<T>(mut self, result: &Result<T, RspamdError>) {
+        let failed = match result {
+            Ok(_) => false,
+            Err(RspamdError::ServerError { status, .. }) => *status >= 500,
+            Err(e) => e.is_transport(),
+        };
+        let slow = self
+            .breaker
+            .settings
+            .slow_request_duration
+            .is_some_and(|d| self.started.elapsed() >= d);
+        self.breaker
+            .record(&self.upstream, self.probe, Outcome { failed, slow });
+        self.probe = false;
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breaker.release(&self.upstream);
+        }
+    }
+}
+
+impl fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("settings", &self.settings)
+            .field("states", &self.states())
+            .finish()
+    }
+}
+
+/// Circuits are not a part of the configuration, breakers with the same settings are equal
+impl PartialEq for CircuitBreaker {
+    fn eq(&self, other: &Self) -> bool {
+        self.settings == other.settings
+    }
+}
+
+impl<'de> Deserialize<'de> for CircuitBreaker {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        CircuitBreakerSettings::deserialize(deserializer).map(CircuitBreaker::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPSTREAM: &str = "http://localhost:11333";
+
+    fn transport_error() -> Result<(), RspamdError> {
+        Err(RspamdError::TransportError {
+            message: "connection refused".to_string(),
+            timeout: false,
+        })
+    }
+
+    #[test]
+    fn test_open_and_recover() {
+        let breaker = CircuitBreaker::new(
+            CircuitBreakerSettings::builder()
+                .window_size(4)
+                .minimum_requests(4)
+                .failure_rate_threshold(0.5)
+                .open_duration(Duration::from_millis(50))
+                .half_open_requests(2)
+                .build(),
+        );
+        for result in [Ok(()), Ok(()), transport_error()] {
+            breaker.acquire(UPSTREAM).unwrap().record(&result);
+        }
+        assert_eq!(breaker.state(UPSTREAM), CircuitState::Closed);
+        // 4xx errors mean that the server is alive
+        breaker
+            .acquire(UPSTREAM)
+            .unwrap()
+            .record::<()>(&Err(RspamdError::AuthorizationError("denied".to_string())));
+        assert_eq!(breaker.state(UPSTREAM), CircuitState::Closed);
+        breaker
+            .acquire(UPSTREAM)
+            .unwrap()
+            .record::<()>(&Err(RspamdError::ServerError {
+                status: 503,
+                message: "unavailable".to_string(),
+            }));
+        assert_eq!(breaker.state(UPSTREAM), CircuitState::Open);
+        assert!(matches!(
+            breaker.acquire(UPSTREAM),
+            Err(RspamdError::CircuitOpen(_))
+        ));
+        assert_eq!(
+            breaker.state("http://other:11333"),
+            CircuitState::Closed,
+            "circuits are per upstream"
+        );
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(breaker.state(UPSTREAM), CircuitState::HalfOpen);
+        let first = breaker.acquire(UPSTREAM).unwrap();
+        let second = breaker.acquire(UPSTREAM).unwrap();
+        assert!(breaker.acquire(UPSTREAM).is_err(), "only two probes");
+        // A cancelled probe frees its slot
+        drop(second);
+        first.record(&Ok(()));
+        breaker.acquire(UPSTREAM).unwrap().record(&Ok(()));
+        assert_eq!(
+            breaker.states(),
+            vec![("http://localhost:11333/".to_string(), CircuitState::Closed)]
+        );
+    }
+
+    #[test]
+    fn test_failed_probe_and_slow_requests() {
+        let breaker = CircuitBreaker::new(
+            CircuitBreakerSettings::builder()
+                .window_size(2)
+                .minimum_requests(2)
+                .slow_request_duration(Duration::ZERO)
+                .slow_request_rate_threshold(1.0)
+                .open_duration(Duration::ZERO)
+                .build(),
+        );
+        breaker.acquire(UPSTREAM).unwrap().record(&Ok(()));
+        breaker.acquire(UPSTREAM).unwrap().record(&Ok(()));
+        // Every request is slow with a zero threshold
+        assert_ne!(breaker.state(UPSTREAM), CircuitState::Closed);
+        breaker
+            .acquire(UPSTREAM)
+            .unwrap()
+            .record(&transport_error());
+        assert_ne!(breaker.state(UPSTREAM), CircuitState::Closed);
+        breaker.reset(UPSTREAM);
+        assert_eq!(breaker.state(UPSTREAM), CircuitState::Closed);
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_fail_fast() {
+        use crate::config::Config;
+        #[cfg(feature = "async")]
+        use crate::scan_async as scan;
+        #[cfg(feature = "sync")]
+        use crate::scan_sync as scan;
+
+        let breaker = CircuitBreaker::new(
+            CircuitBreakerSettings::builder()
+                .window_size(2)
+                .minimum_requests(2)
+                .build(),
+        );
+        let config = Config::builder()
+            .base_url("http://127.0.0.1:1".to_string())
+            .retries(1)
+            .circuit_breaker(breaker.clone())
+            .build();
+        for _ in 0..2 {
+            let e = scan(&config, "Test", Default::default()).await.unwrap_err();
+            assert_eq!(e.kind(), "transport");
+        }
+        assert_eq!(breaker.state(&config.base_url), CircuitState::Open);
+        let e = scan(&config, "Test", Default::default()).await.unwrap_err();
+        assert!(
+            matches!(e, RspamdError::CircuitOpen(_)),
+            "unexpected error: {}",
+            e
+        );
+    }
+}
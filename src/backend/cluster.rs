@@ -0,0 +1,210 @@
+//! Cluster-wide view of controllers, similar to the web UI: requests are sent to all
+//! neighbours concurrently and replies are merged. Unreachable nodes are reported
+//! separately and do not fail the whole request.
+
+use crate::backend::controller::Controller;
+use crate::config::Config;
+use crate::error::RspamdError;
+use crate::protocol::controller::{merge_counters, merge_history, HistoryRow, Stat, SymbolCounter};
+
+/// Merged reply of a cluster request
+#[derive(Debug)]
+pub struct ClusterReply<T> {
+    /// Replies of all reachable nodes merged together
+    pub merged: T,
+    /// Nodes that have replied
+    pub nodes: Vec<String>,
+    /// Nodes that have failed with their errors
+    pub errors: Vec<(String, RspamdError)>,
+}
+
+impl<T> ClusterReply<T> {
+    /// Whether all nodes have replied
+    pub fn is_complete(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Request sent to each node
+enum Query {
+    Stat,
+    Counters,
+    History { from: usize, to: usize },
+}
+
+/// Reply of a single node
+enum NodeReply {
+    Stat(Box<Stat>),
+    Counters(Vec<SymbolCounter>),
+    History(Vec<HistoryRow>),
+}
+
+/// Replies, names of nodes that have replied, and errors of failed nodes
+type FanOut = (Vec<NodeReply>, Vec<String>, Vec<(String, RspamdError)>);
+
+#[maybe_async::maybe_async]
+async fn query_node(config: &Config, query: &Query) -> Result<NodeReply, RspamdError> {
+    let controller = Controller::new(config);
+    Ok(match *query {
+        Query::Stat => NodeReply::Stat(Box::new(controller.stat().await?)),
+        Query::Counters => NodeReply::Counters(controller.counters().await?),
+        Query::History { from, to } => NodeReply::History(controller.history(from, to).await?.rows),
+    })
+}
+
+/// Set of controllers, usually discovered from neighbours of one of them
+pub struct Cluster {
+    nodes: Vec<(String, Config)>,
+}
+
+impl Cluster {
+    /// Cluster of named nodes
+    pub fn new(nodes: Vec<(String, Config)>) -> Self {
+        Self { nodes }
+    }
+
+    /// Discover the cluster from neighbours of the controller at `config.base_url`.
+    /// Neighbours share the rest of `config` (password, encryption key, timeouts).
+    /// If no neighbours are configured, the cluster consists of this controller only.
+    #[maybe_async::maybe_async]
+    pub async fn discover(config: &Config) -> Result<Self, RspamdError> {
+        let neighbours = Controller::new(config).neighbours().await?;
+        let mut nodes = Vec::with_capacity(neighbours.len().max(1));
+        for (name, neighbour) in neighbours {
+            let base_url = neighbour.base_url(&config.base_url)?;
+            nodes.push((name, config.with_base_url(base_url)));
+        }
+        if nodes.is_empty() {
+            nodes.push((
+                "local".to_string(),
+                config.with_base_url(config.base_url.clone()),
+            ));
+        }
+        nodes.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(Self { nodes })
+    }
+
+    /// Names of nodes
+    pub fn nodes(&self) -> impl Iterator<Item = &str> {
+        self.nodes.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Statistics of all nodes, summed
+    #[maybe_async::maybe_async]
+    pub async fn stat(&self) -> ClusterReply<Stat> {
+        let (replies, nodes, errors) = self.fan_out(Query::Stat).await;
+        let mut merged = Stat::default();
+        for reply in replies {
+            if let NodeReply::Stat(stat) = reply {
+                merged.merge(&stat);
+            }
+        }
+        ClusterReply {
+            merged,
+            nodes,
+            errors,
+        }
+    }
+
+    /// Symbol counters of all nodes, merged by symbol
+    #[maybe_async::maybe_async]
+    pub async fn counters(&self) -> ClusterReply<Vec<SymbolCounter>> {
+        let (replies, nodes, errors) = self.fan_out(Query::Counters).await;
+        let merged = merge_counters(replies.into_iter().filter_map(|reply| match reply {
+            NodeReply::Counters(counters) => Some(counters),
+            _ => None,
+        }));
+        ClusterReply {
+            merged,
+            nodes,
+            errors,
+        }
+    }
+
+    /// History rows `from..to` of each node, merged with the most recent first
+    #[maybe_async::maybe_async]
+    pub async fn history(&self, from: usize, to: usize) -> ClusterReply<Vec<HistoryRow>> {
+        let (replies, nodes, errors) = self.fan_out(Query::History { from, to }).await;
+        let merged = merge_history(replies.into_iter().filter_map(|reply| match reply {
+            NodeReply::History(rows) => Some(rows),
+            _ => None,
+        }));
+        ClusterReply {
+            merged,
+            nodes,
+            errors,
+        }
+    }
+
+    #[cfg(feature = "async")]
+    async fn fan_out(&self, query: Query) -> FanOut {
+        let requests = self
+            .nodes
+            .iter()
+            .map(|(_, config)| query_node(config, &query));
+        let results = futures::future::join_all(requests).await;
+        self.split(results)
+    }
+
+    #[cfg(feature = "sync")]
+    fn fan_out(&self, query: Query) -> FanOut {
+        let query = &query;
+        let results = std::thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .nodes
+                .iter()
+                .map(|(_, config)| scope.spawn(move || query_node(config, query)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap_or(Err(RspamdError::Unknown)))
+                .collect::<Vec<_>>()
+        });
+        self.split(results)
+    }
+
+    /// Match node results with node names
+    fn split(&self, results: Vec<Result<NodeReply, RspamdError>>) -> FanOut {
+        let mut replies = Vec::new();
+        let mut nodes = Vec::new();
+        let mut errors = Vec::new();
+        for ((name, _), result) in self.nodes.iter().zip(results) {
+            match result {
+                Ok(reply) => {
+                    replies.push(reply);
+                    nodes.push(name.clone());
+                }
+                Err(e) => errors.push((name.clone(), e)),
+            }
+        }
+        (replies, nodes, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_unreachable_nodes() {
+        let config = Config::builder()
+            .base_url("http://127.0.0.1:1".to_string())
+            .retries(1)
+            .build();
+        let cluster = Cluster::new(vec![
+            (
+                "a".to_string(),
+                config.with_base_url("http://127.0.0.1:1".to_string()),
+            ),
+            (
+                "b".to_string(),
+                config.with_base_url("http://127.0.0.1:2".to_string()),
+            ),
+        ]);
+        let reply = cluster.stat().await;
+        assert!(!reply.is_complete());
+        assert!(reply.nodes.is_empty());
+        assert_eq!(reply.errors.len(), 2);
+        assert_eq!(reply.merged.scanned, 0);
+    }
+}
@@ -0,0 +1,487 @@
+//! Typed operations of the Rspamd controller worker (port 11334 by default).
+//!
+//! The controller distinguishes two passwords: `password` allows read-only commands, while
+//! `enable_password` is required for commands that modify the state of the server, such as
+//! learning, `/savemap`, `/historyreset`, `/saveactions` or `/savesymbols`. The password from
+//! `Config` is sent in both cases, so it must be the `enable_password` for modifying commands;
+//! otherwise they fail with `RspamdError::AuthorizationError`.
+
+use crate::config::{Config, EnvelopeData, LearnOptions};
+use crate::error::RspamdError;
+use crate::protocol::commands::{RspamdCommand, RspamdEndpoint};
+use crate::protocol::controller::{
+    symbol_scores_body, ActionThresholds, History, HistoryRow, LearnReply, MapInfo, Neighbour,
+    SelectorReply, Stat, SuccessReply, SymbolCounter,
+};
+use crate::protocol::prometheus::PrometheusMetrics;
+use std::collections::HashMap;
+
+#[cfg(feature = "async")]
+use crate::backend::async_client::request_async as request;
+#[cfg(feature = "sync")]
+use crate::backend::sync_client::request_sync as request;
+
+/// Maximum number of rows returned by `Controller::history_all`
+pub const HISTORY_MAX_ROWS: usize = 100_000;
+
+/// Client for the controller worker
+pub struct Controller<'a> {
+    config: &'a Config,
+}
+
+/// Envelope with a single extra header, used to pass arguments of controller commands
+fn header(name: &str, value: String) -> EnvelopeData {
+    EnvelopeData::builder()
+        .additional_headers(HashMap::from([(name.to_string(), value)]))
+        .build()
+}
+
+/// Query string with the selector, as expected by the selectors plugin
+fn selector_query(selector: &str) -> String {
+    url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("selector", selector)
+        .finish()
+}
+
+/// Selector errors are reported as `{"success": false}` or with an error status
+fn selector_error(selector: &str, e: RspamdError) -> RspamdError {
+    match e {
+        RspamdError::ServerError { message, .. } if message.contains("selector") => {
+            RspamdError::InvalidSelector(format!("{}: {}", selector, message))
+        }
+        e => e,
+    }
+}
+
+/// Check acknowledgement of a modifying command
+fn acknowledge(body: &[u8], command: &str) -> Result<SuccessReply, RspamdError> {
+    let reply: SuccessReply = serde_json::from_slice(body)?;
+    if reply.success {
+        Ok(reply)
+    } else {
+        Err(RspamdError::ServerError {
+            status: 200,
+            message: format!("{} has failed", command),
+        })
+    }
+}
+
+#[maybe_async::maybe_async]
+impl<'a> Controller<'a> {
+    /// Create a controller client, `config.base_url` must point to the controller worker
+    pub fn new(config: &'a Config) -> Self {
+        Self { config }
+    }
+
+    /// List maps known by the controller
+    pub async fn maps(&self) -> Result<Vec<MapInfo>, RspamdError> {
+        let endpoint = RspamdEndpoint::from_command(RspamdCommand::Maps);
+        let body = request(self.config, endpoint, &[][..], Default::default()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Get content of the map with id `map_id`
+    pub async fn get_map(&self, map_id: u32) -> Result<String, RspamdError> {
+        let endpoint = RspamdEndpoint::from_command(RspamdCommand::GetMap);
+        let body = request(
+            self.config,
+            endpoint,
+            &[][..],
+            header("Map", map_id.to_string()),
+        )
+        .await?;
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    /// Replace content of the map with id `map_id`, requires `enable_password`
+    pub async fn save_map(&self, map_id: u32, content: &str) -> Result<SuccessReply, RspamdError> {
+        let endpoint = RspamdEndpoint::from_command(RspamdCommand::SaveMap);
+        let body = request(
+            self.config,
+            endpoint,
+            content.as_bytes(),
+            header("Map", map_id.to_string()),
+        )
+        .await?;
+        acknowledge(&body, "/savemap")
+    }
+
+    /// Get scan history rows from `from` (inclusive) to `to` (exclusive), most recent first.
+    /// Rspamd treats both bounds as inclusive (Redis `LRANGE`), so `to - 1` is sent.
+    pub async fn history(&self, from: usize, to: usize) -> Result<History, RspamdError> {
+        if to <= from {
+            return Ok(History {
+                version: 0,
+                rows: Vec::new(),
+                total: None,
+            });
+        }
+        let endpoint = RspamdEndpoint::from_command(RspamdCommand::History).with_query(format!(
+            "from={}&to={}",
+            from,
+            to - 1
+        ));
+        let body = request(self.config, endpoint, &[][..], Default::default()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Get the whole scan history, fetching it by pages of `page_size` rows.
+    /// At most `HISTORY_MAX_ROWS` rows are returned; fetching stops early if the server
+    /// ignores paging and returns the same rows again.
+    pub async fn history_all(&self, page_size: usize) -> Result<Vec<HistoryRow>, RspamdError> {
+        let page_size = page_size.max(1);
+        let mut rows: Vec<HistoryRow> = Vec::new();
+        loop {
+            let page = self.history(rows.len(), rows.len() + page_size).await?;
+            if page.rows.is_empty() || rows.ends_with(&page.rows) {
+                break;
+            }
+            let last = page.rows.len() < page_size
+                || page
+                    .total
+                    .is_some_and(|total| rows.len() + page.rows.len() >= total as usize)
+                || rows.len() + page.rows.len() >= HISTORY_MAX_ROWS;
+            rows.extend(page.rows);
+            if last {
+                break;
+            }
+        }
+        rows.truncate(HISTORY_MAX_ROWS);
+        Ok(rows)
+    }
+
+    /// Clear scan history, requires `enable_password`
+    pub async fn history_reset(&self) -> Result<SuccessReply, RspamdError> {
+        let endpoint = RspamdEndpoint::from_command(RspamdCommand::HistoryReset);
+        let body = request(self.config, endpoint, &[][..], Default::default()).await?;
+        acknowledge(&body, "/historyreset")
+    }
+
+    /// Save action thresholds, requires `enable_password`.
+    /// Thresholds are validated before sending, see `ActionThresholds::validate`.
+    pub async fn save_actions(
+        &self,
+        thresholds: &ActionThresholds,
+    ) -> Result<SuccessReply, RspamdError> {
+        thresholds.validate()?;
+        let body = serde_json::to_vec(&thresholds.to_array())?;
+        let endpoint = RspamdEndpoint::from_command(RspamdCommand::SaveActions);
+        let body = request(self.config, endpoint, body, Default::default()).await?;
+        acknowledge(&body, "/saveactions")
+    }
+
+    /// Save scores of symbols, requires `enable_password`
+    pub async fn save_symbols(
+        &self,
+        scores: &HashMap<String, f64>,
+    ) -> Result<SuccessReply, RspamdError> {
+        let body = symbol_scores_body(scores)?;
+        let endpoint = RspamdEndpoint::from_command(RspamdCommand::SaveSymbols);
+        let body = request(self.config, endpoint, body, Default::default()).await?;
+        acknowledge(&body, "/savesymbols")
+    }
+
+    /// Get server statistics
+    pub async fn stat(&self) -> Result<Stat, RspamdError> {
+        let endpoint = RspamdEndpoint::from_command(RspamdCommand::Stat);
+        let body = request(self.config, endpoint, &[][..], Default::default()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Get and parse metrics in Prometheus format
+    pub async fn metrics(&self) -> Result<PrometheusMetrics, RspamdError> {
+        let endpoint = RspamdEndpoint::from_command(RspamdCommand::Metrics);
+        let body = request(self.config, endpoint, &[][..], Default::default()).await?;
+        PrometheusMetrics::parse(std::str::from_utf8(&body)?)
+    }
+
+    /// Get metrics, converting `/stat` reply if the server does not support `/metrics`
+    pub async fn metrics_or_stat(&self) -> Result<PrometheusMetrics, RspamdError> {
+        match self.metrics().await {
+            Err(RspamdError::ServerError { status: 404, .. }) => {
+                Ok(self.stat().await?.to_metrics())
+            }
+            result => result,
+        }
+    }
+
+    /// Get neighbours of the controller by name
+    pub async fn neighbours(&self) -> Result<HashMap<String, Neighbour>, RspamdError> {
+        let endpoint = RspamdEndpoint::from_command(RspamdCommand::Neighbours);
+        let body = request(self.config, endpoint, &[][..], Default::default()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Get symbol counters
+    pub async fn counters(&self) -> Result<Vec<SymbolCounter>, RspamdError> {
+        let endpoint = RspamdEndpoint::from_command(RspamdCommand::Counters);
+        let body = request(self.config, endpoint, &[][..], Default::default()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Learn a message as spam, requires `enable_password`
+    pub async fn learn_spam<B: AsRef<[u8]> + Send>(
+        &self,
+        body: B,
+        options: LearnOptions,
+    ) -> Result<LearnReply, RspamdError> {
+        self.learn(RspamdCommand::Learnspam, body, options).await
+    }
+
+    /// Learn a message as ham, requires `enable_password`
+    pub async fn learn_ham<B: AsRef<[u8]> + Send>(
+        &self,
+        body: B,
+        options: LearnOptions,
+    ) -> Result<LearnReply, RspamdError> {
+        self.learn(RspamdCommand::Learnham, body, options).await
+    }
+
+    async fn learn<B: AsRef<[u8]> + Send>(
+        &self,
+        command: RspamdCommand,
+        body: B,
+        options: LearnOptions,
+    ) -> Result<LearnReply, RspamdError> {
+        let classifier = options.classifier.clone();
+        let endpoint = RspamdEndpoint::from_command(command);
+        let body = request(self.config, endpoint, body, options.into()).await?;
+        let reply: LearnReply = serde_json::from_slice(&body)?;
+        Ok(reply.with_classifier(classifier.as_deref()))
+    }
+
+    /// Check that a selector can be parsed by the server
+    pub async fn check_selector(&self, selector: &str) -> Result<(), RspamdError> {
+        let endpoint = RspamdEndpoint::from_command(RspamdCommand::CheckSelector)
+            .with_query(selector_query(selector));
+        let body = request(self.config, endpoint, &[][..], Default::default())
+            .await
+            .map_err(|e| selector_error(selector, e))?;
+        let reply: SelectorReply = serde_json::from_slice(&body)?;
+        if reply.success {
+            Ok(())
+        } else {
+            Err(RspamdError::InvalidSelector(selector.to_string()))
+        }
+    }
+
+    /// Extract values of a selector from a message, an invalid selector results in
+    /// `RspamdError::InvalidSelector`
+    pub async fn check_message<B: AsRef<[u8]> + Send>(
+        &self,
+        selector: &str,
+        body: B,
+    ) -> Result<Vec<String>, RspamdError> {
+        let endpoint = RspamdEndpoint::from_command(RspamdCommand::CheckMessage)
+            .with_query(selector_query(selector));
+        let body = request(self.config, endpoint, body, Default::default())
+            .await
+            .map_err(|e| selector_error(selector, e))?;
+        let reply: SelectorReply = serde_json::from_slice(&body)?;
+        if reply.success {
+            Ok(reply.data)
+        } else {
+            Err(RspamdError::InvalidSelector(selector.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// Raw request received by `serve_once`: header lines and body
+    type Received = (Vec<String>, Vec<u8>);
+
+    /// Serve a single request with `reply`, the received request is sent to the channel
+    fn serve_once(reply: &'static str) -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            let received = loop {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let mut hdrs = [httparse::EMPTY_HEADER; 64];
+                let mut parsed = httparse::Request::new(&mut hdrs);
+                if let httparse::Status::Complete(offset) = parsed.parse(&request).unwrap() {
+                    let headers = parsed
+                        .headers
+                        .iter()
+                        .map(|h| format!("{}: {}", h.name, String::from_utf8_lossy(h.value)))
+                        .collect::<Vec<_>>();
+                    let length = parsed
+                        .headers
+                        .iter()
+                        .find(|h| h.name.eq_ignore_ascii_case("Content-Length"))
+                        .map_or(0, |h| {
+                            std::str::from_utf8(h.value).unwrap().parse().unwrap()
+                        });
+                    if request.len() >= offset + length {
+                        break (headers, request[offset..offset + length].to_vec());
+                    }
+                }
+            };
+            let reply = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                reply.len(),
+                reply
+            );
+            stream.write_all(reply.as_bytes()).unwrap();
+            tx.send(received).unwrap();
+        });
+        (base_url, rx)
+    }
+
+    /// Bodies of modifying commands are stored by the controller as is, so they must not be
+    /// compressed even if `zstd` is enabled
+    fn assert_plain(received: &Received, body: &[u8]) {
+        let (headers, received_body) = received;
+        assert_eq!(received_body.as_slice(), body);
+        assert!(!headers.iter().any(|h| {
+            let h = h.to_ascii_lowercase();
+            h.starts_with("compression:") || h.starts_with("content-encoding:")
+        }));
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_save_map_plain_body() {
+        let (base_url, rx) = serve_once(r#"{"success":true}"#);
+        let config = Config::builder().base_url(base_url).retries(1).build();
+        assert!(config.zstd);
+        let map = "example.com\nexample.net\n";
+        let reply = Controller::new(&config).save_map(1, map).await.unwrap();
+        assert!(reply.success);
+        assert_plain(&rx.recv().unwrap(), map.as_bytes());
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_save_actions_and_symbols_plain_json() {
+        let (base_url, rx) = serve_once(r#"{"success":true}"#);
+        let config = Config::builder().base_url(base_url).retries(1).build();
+        let thresholds = ActionThresholds::builder()
+            .reject(15.0)
+            .add_header(6.0)
+            .build();
+        let reply = Controller::new(&config).save_actions(&thresholds).await;
+        assert!(reply.unwrap().success);
+        assert_plain(&rx.recv().unwrap(), b"[15.0,null,6.0,null]");
+
+        let (base_url, rx) = serve_once(r#"{"success":true}"#);
+        let config = Config::builder().base_url(base_url).retries(1).build();
+        let scores = HashMap::from([("BAYES_SPAM".to_string(), 5.5)]);
+        let reply = Controller::new(&config).save_symbols(&scores).await;
+        assert!(reply.unwrap().success);
+        assert_plain(
+            &rx.recv().unwrap(),
+            br#"[{"name":"BAYES_SPAM","value":5.5}]"#,
+        );
+    }
+
+    /// Serve `/history` with the same rows whatever the requested page, as the legacy
+    /// (non-Redis) history does
+    fn serve_history(requests: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let row = r#"{"score":1.0,"action":"no action","unix_time":1700000000.0}"#;
+            let body = format!(r#"{{"version":2,"rows":[{},{},{}]}}"#, row, row, row);
+            for _ in 0..requests {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let reply = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+        base_url
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_history_all_ignored_paging() {
+        let config = Config::builder()
+            .base_url(serve_history(2))
+            .retries(1)
+            .build();
+        let rows = Controller::new(&config).history_all(2).await.unwrap();
+        assert_eq!(rows.len(), 3);
+    }
+
+    /// Serve `/history` like the Redis history does: rows `from..=to` of `total` rows, each
+    /// row has its index as `unix_time`
+    fn serve_redis_history(total: usize, requests: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for _ in 0..requests {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let line = String::from_utf8_lossy(&request)
+                    .lines()
+                    .next()
+                    .unwrap()
+                    .to_string();
+                let path = line.split(' ').nth(1).unwrap();
+                let url = url::Url::parse(&format!("http://localhost{}", path)).unwrap();
+                let arg = |name: &str| -> usize {
+                    url.query_pairs()
+                        .find(|(k, _)| k == name)
+                        .map(|(_, v)| v.parse().unwrap())
+                        .unwrap()
+                };
+                let (from, to) = (arg("from"), arg("to").min(total - 1));
+                let rows = (from..=to)
+                    .map(|i| format!(r#"{{"score":1.0,"action":"no action","unix_time":{}}}"#, i))
+                    .collect::<Vec<_>>();
+                let body = format!(
+                    r#"{{"version":2,"rows":[{}],"total":{}}}"#,
+                    rows.join(","),
+                    total
+                );
+                let reply = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+        base_url
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_history_pages_do_not_overlap() {
+        let config = Config::builder()
+            .base_url(serve_redis_history(7, 4))
+            .retries(1)
+            .build();
+        let controller = Controller::new(&config);
+        let page = controller.history(0, 3).await.unwrap();
+        assert_eq!(page.rows.len(), 3);
+        let page = controller.history(3, 3).await.unwrap();
+        assert!(page.rows.is_empty());
+
+        let rows = controller.history_all(3).await.unwrap();
+        let times = rows.iter().map(|row| row.unix_time).collect::<Vec<_>>();
+        assert_eq!(times, vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    }
+}
@@ -0,0 +1,273 @@
+//! Decoding of replies shared by both backends: decryption of HTTPCrypt replies and
+//! decompression of zstd and gzip bodies.
+//!
+//! Depending on the version and the worker, Rspamd announces compressed replies either with
+//! its own `Compression` header or with the standard `Content-Encoding` one, so both are
+//! checked. HTTP clients must not decompress replies themselves.
+
+use crate::error::RspamdError;
+use crate::protocol::encryption::{httpcrypt_decrypt, RspamdNM};
+use crate::telemetry;
+use std::io::Read;
+use std::time::Instant;
+
+/// Reply headers as name and value pairs
+pub(crate) type Headers = Vec<(String, String)>;
+
+/// Encoding of a reply body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Identity,
+    Zstd,
+    Gzip,
+}
+
+/// Encoding announced by either `Compression` or `Content-Encoding` header
+fn reply_encoding(headers: &Headers) -> Result<Encoding, RspamdError> {
+    let value = headers.iter().find_map(|(name, value)| {
+        (name.eq_ignore_ascii_case("Compression") || name.eq_ignore_ascii_case("Content-Encoding"))
+            .then(|| value.trim())
+    });
+    match value {
+        None => Ok(Encoding::Identity),
+        Some(v) if v.is_empty() || v.eq_ignore_ascii_case("identity") => Ok(Encoding::Identity),
+        Some(v) if v.eq_ignore_ascii_case("zstd") => Ok(Encoding::Zstd),
+        Some(v) if v.eq_ignore_ascii_case("gzip") || v.eq_ignore_ascii_case("x-gzip") => {
+            Ok(Encoding::Gzip)
+        }
+        Some(v) => Err(RspamdError::HttpError(format!(
+            "Unsupported reply encoding: {}",
+            v
+        ))),
+    }
+}
+
+/// Decrypt the reply with `shared_key` and parse the inner HTTP reply
+fn decrypt(mut body: Vec<u8>, shared_key: RspamdNM) -> Result<(Headers, Vec<u8>), RspamdError> {
+    let decrypted_offset = httpcrypt_decrypt(body.as_mut_slice(), shared_key)?;
+    let mut hdrs = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Response::new(&mut hdrs);
+    let body_offset = match parsed
+        .parse(&body[decrypted_offset..])
+        .map_err(|e| RspamdError::HttpError(e.to_string()))?
+    {
+        httparse::Status::Complete(offset) => offset,
+        httparse::Status::Partial => {
+            return Err(RspamdError::HttpError(
+                "Truncated encrypted reply".to_string(),
+            ))
+        }
+    };
+    let headers = parsed
+        .headers
+        .iter()
+        .map(|hdr| {
+            Ok((
+                hdr.name.to_string(),
+                std::str::from_utf8(hdr.value)?.to_string(),
+            ))
+        })
+        .collect::<Result<Headers, RspamdError>>()?;
+    Ok((headers, body.split_off(decrypted_offset + body_offset)))
+}
+
+/// Decode a reply: if `shared_key` is set, the body is decrypted and the inner headers replace
+/// `headers`; then the body is decompressed according to the headers, which are removed from
+/// the returned headers.
+pub(crate) fn decode_response(
+    headers: Headers,
+    body: Vec<u8>,
+    shared_key: Option<RspamdNM>,
+) -> Result<(Headers, Vec<u8>), RspamdError> {
+    let started = Instant::now();
+    let encrypted = shared_key.is_some();
+    let wire_size = body.len();
+    let (mut headers, body) = match shared_key {
+        Some(shared_key) => decrypt(body, shared_key)?,
+        None => (headers, body),
+    };
+    let body = match reply_encoding(&headers)? {
+        Encoding::Identity => body,
+        Encoding::Zstd => zstd::decode_all(body.as_slice())?,
+        Encoding::Gzip => {
+            let mut decoded = Vec::with_capacity(body.len() * 4);
+            flate2::read::GzDecoder::new(body.as_slice()).read_to_end(&mut decoded)?;
+            decoded
+        }
+    };
+    headers.retain(|(name, _)| {
+        !name.eq_ignore_ascii_case("Compression") && !name.eq_ignore_ascii_case("Content-Encoding")
+    });
+    telemetry::response_decoded(encrypted, wire_size, body.len(), started.elapsed());
+    Ok((headers, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::protocol::encryption::{httpcrypt_server_decrypt, httpcrypt_server_encrypt};
+    use crate::protocol::keypair::RspamdKeypair;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    #[cfg(feature = "async")]
+    use crate::scan_async as scan;
+    #[cfg(feature = "sync")]
+    use crate::scan_sync as scan;
+
+    const REPLY: &[u8] = br#"{"action":"reject","score":15.5,"required_score":15.0}"#;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn encode(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+        match encoding {
+            Encoding::Identity => data.to_vec(),
+            Encoding::Zstd => zstd::encode_all(data, 3).unwrap(),
+            Encoding::Gzip => gzip(data),
+        }
+    }
+
+    fn encoding_name(encoding: Encoding) -> &'static str {
+        match encoding {
+            Encoding::Identity => "identity",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Serve a single scan request with `REPLY` encoded with `encoding` and announced with
+    /// `header`, replies are encrypted if the request was
+    fn serve_once(
+        server: Option<RspamdKeypair>,
+        encoding: Encoding,
+        header: &'static str,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            let (headers, body) = loop {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let mut hdrs = [httparse::EMPTY_HEADER; 64];
+                let mut parsed = httparse::Request::new(&mut hdrs);
+                if let httparse::Status::Complete(offset) = parsed.parse(&request).unwrap() {
+                    let headers: Headers = parsed
+                        .headers
+                        .iter()
+                        .map(|h| {
+                            (
+                                h.name.to_string(),
+                                String::from_utf8_lossy(h.value).into_owned(),
+                            )
+                        })
+                        .collect();
+                    let length = headers
+                        .iter()
+                        .find(|(k, _)| k.eq_ignore_ascii_case("Content-Length"))
+                        .map_or(0, |(_, v)| v.parse::<usize>().unwrap());
+                    if request.len() >= offset + length {
+                        break (headers, request[offset..offset + length].to_vec());
+                    }
+                }
+            };
+
+            let key = headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case("Key"))
+                .map(|(_, v)| v.clone());
+            let reply_headers = [(header, encoding_name(encoding))];
+            let payload = encode(encoding, REPLY);
+            let encrypted = server.is_some();
+            let body = match (key, server) {
+                (Some(key), Some(server)) => {
+                    let mut body = body;
+                    let request =
+                        httpcrypt_server_decrypt(&key, &mut body, server.secret_key()).unwrap();
+                    httpcrypt_server_encrypt(200, reply_headers, &payload, &request.shared_key)
+                }
+                _ => payload,
+            };
+            let mut reply = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n",
+                body.len()
+            );
+            if !encrypted {
+                reply.push_str(&format!("{}: {}\r\n", header, encoding_name(encoding)));
+            }
+            reply.push_str("\r\n");
+            stream.write_all(reply.as_bytes()).unwrap();
+            stream.write_all(&body).unwrap();
+        });
+        base_url
+    }
+
+    #[maybe_async::maybe_async]
+    async fn check_scan(encrypted: bool, encoding: Encoding, header: &'static str) {
+        let server = encrypted.then(RspamdKeypair::generate);
+        let encryption_key = server.as_ref().map(|s| s.public_key().to_string());
+        let base_url = serve_once(server, encoding, header);
+        let mut config = Config::builder().base_url(base_url).retries(1).build();
+        config.encryption_key = encryption_key;
+        let reply = scan(
+            &config,
+            "From: user@example.com\n\nTest",
+            Default::default(),
+        )
+        .await
+        .unwrap_or_else(|e| panic!("{:?} via {}: {}", encoding, header, e));
+        assert_eq!(reply.action, "reject");
+        assert_eq!(reply.score, 15.5);
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_plain_replies() {
+        for header in ["Compression", "Content-Encoding"] {
+            for encoding in [Encoding::Identity, Encoding::Zstd, Encoding::Gzip] {
+                check_scan(false, encoding, header).await;
+            }
+        }
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_encrypted_replies() {
+        for header in ["Compression", "Content-Encoding"] {
+            for encoding in [Encoding::Identity, Encoding::Zstd, Encoding::Gzip] {
+                check_scan(true, encoding, header).await;
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let headers = vec![
+            ("content-encoding".to_string(), "GZIP".to_string()),
+            ("Content-Type".to_string(), "application/json".to_string()),
+        ];
+        let (headers, body) = decode_response(headers, gzip(REPLY), None).unwrap();
+        assert_eq!(body, REPLY);
+        assert_eq!(
+            headers,
+            vec![("Content-Type".to_string(), "application/json".to_string())]
+        );
+
+        let headers = vec![("Compression".to_string(), "zstd".to_string())];
+        let (headers, body) =
+            decode_response(headers, zstd::encode_all(REPLY, 1).unwrap(), None).unwrap();
+        assert_eq!(body, REPLY);
+        assert!(headers.is_empty());
+
+        let (_, body) = decode_response(Vec::new(), REPLY.to_vec(), None).unwrap();
+        assert_eq!(body, REPLY);
+
+        let headers = vec![("Content-Encoding".to_string(), "br".to_string())];
+        assert!(decode_response(headers, REPLY.to_vec(), None).is_err());
+    }
+}
@@ -0,0 +1,608 @@
+//! Upstream discovery from DNS SRV records, e.g. `_rspamd._tcp.example.com`.
+//!
+//! Records are resolved on first use and refreshed when their TTL expires. Scans are sent
+//! to upstreams of the lowest priority with smooth weighted round-robin over record weights
+//! (RFC 2782). If a refresh fails, the previous upstreams are kept and the refresh is retried
+//! after `min_ttl`. Only one lookup is in flight at a time, stale upstreams are served
+//! meanwhile. Targets `.` mean that the service is decidedly not available (RFC 2782) and are
+//! skipped, an answer made only of them leaves no upstreams. Resolvers are pluggable: `StaticResolver` serves records from memory,
+//! `DnsResolver` (async client with the `srv` feature) queries the system resolver.
+
+#[cfg(feature = "async")]
+use crate::backend::async_client::scan_async as scan;
+#[cfg(feature = "sync")]
+use crate::backend::sync_client::scan_sync as scan;
+use crate::config::{Config, EnvelopeData};
+use crate::error::RspamdError;
+use crate::protocol::RspamdScanReply;
+use crate::telemetry;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use typed_builder::TypedBuilder;
+
+/// Target of a SRV record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvTarget {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Resolved SRV records
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvAnswer {
+    pub targets: Vec<SrvTarget>,
+    /// Time to live of the records
+    pub ttl: Duration,
+}
+
+/// Resolver of SRV records
+#[maybe_async::maybe_async]
+pub trait SrvResolver: Send + Sync {
+    async fn resolve(&self, name: &str) -> Result<SrvAnswer, RspamdError>;
+}
+
+/// In-memory resolver, records can be changed at any time
+#[derive(Debug, Default)]
+pub struct StaticResolver {
+    records: Mutex<HashMap<String, SrvAnswer>>,
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set records of a service
+    pub fn set(&self, name: &str, answer: SrvAnswer) {
+        self.records().insert(name.to_string(), answer);
+    }
+
+    /// Remove records of a service, it will fail to resolve
+    pub fn remove(&self, name: &str) {
+        self.records().remove(name);
+    }
+
+    fn records(&self) -> MutexGuard<'_, HashMap<String, SrvAnswer>> {
+        self.records.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[maybe_async::maybe_async]
+impl SrvResolver for StaticResolver {
+    async fn resolve(&self, name: &str) -> Result<SrvAnswer, RspamdError> {
+        self.records()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| RspamdError::DiscoveryError(format!("No SRV records for {}", name)))
+    }
+}
+
+/// Resolver using the system DNS configuration
+#[cfg(all(feature = "srv", feature = "async"))]
+pub struct DnsResolver {
+    resolver: hickory_resolver::TokioResolver,
+}
+
+#[cfg(all(feature = "srv", feature = "async"))]
+impl DnsResolver {
+    /// Resolver configured from `/etc/resolv.conf` or its platform equivalent
+    pub fn from_system_conf() -> Result<Self, RspamdError> {
+        let resolver = hickory_resolver::TokioResolver::builder_tokio()
+            .map_err(|e| RspamdError::DiscoveryError(e.to_string()))?
+            .build();
+        Ok(Self { resolver })
+    }
+}
+
+#[cfg(all(feature = "srv", feature = "async"))]
+#[maybe_async::maybe_async]
+impl SrvResolver for DnsResolver {
+    async fn resolve(&self, name: &str) -> Result<SrvAnswer, RspamdError> {
+        let lookup = self
+            .resolver
+            .srv_lookup(name)
+            .await
+            .map_err(|e| RspamdError::DiscoveryError(format!("{}: {}", name, e)))?;
+        let ttl = lookup
+            .as_lookup()
+            .valid_until()
+            .saturating_duration_since(Instant::now());
+        let targets = lookup
+            .iter()
+            .map(|srv| SrvTarget {
+                priority: srv.priority(),
+                weight: srv.weight(),
+                port: srv.port(),
+                target: srv.target().to_utf8(),
+            })
+            .collect();
+        Ok(SrvAnswer { targets, ttl })
+    }
+}
+
+/// Discovery options
+#[derive(TypedBuilder, Debug, Clone, PartialEq)]
+pub struct DiscoveryOptions {
+    /// Scheme of upstream URLs
+    #[builder(default = "http".to_string())]
+    pub scheme: String,
+
+    /// Lower bound of the refresh interval, also the delay before retrying a failed refresh
+    #[builder(default = Duration::from_secs(5))]
+    pub min_ttl: Duration,
+
+    /// Upper bound of the refresh interval
+    #[builder(default = Duration::from_secs(300))]
+    pub max_ttl: Duration,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+struct Upstream {
+    config: Arc<Config>,
+    priority: u16,
+    weight: i64,
+    current: i64,
+}
+
+#[derive(Default)]
+struct State {
+    upstreams: Vec<Upstream>,
+    expires_at: Option<Instant>,
+}
+
+impl State {
+    /// Smooth weighted round-robin over upstreams of the lowest priority
+    fn select(&mut self) -> Option<Arc<Config>> {
+        let priority = self.upstreams.iter().map(|u| u.priority).min()?;
+        let group = self
+            .upstreams
+            .iter_mut()
+            .filter(|u| u.priority == priority)
+            .collect::<Vec<_>>();
+        // Records with zero weight are only used if all of the group have zero weight
+        let all_zero = group.iter().all(|u| u.weight == 0);
+        let mut total = 0;
+        let mut best: Option<&mut Upstream> = None;
+        for upstream in group {
+            let weight = if all_zero { 1 } else { upstream.weight };
+            upstream.current += weight;
+            total += weight;
+            if best.as_ref().is_none_or(|b| upstream.current > b.current) {
+                best = Some(upstream);
+            }
+        }
+        let best = best?;
+        best.current -= total;
+        Some(best.config.clone())
+    }
+}
+
+/// Lock held while records are resolved
+#[cfg(feature = "async")]
+type RefreshLock = tokio::sync::Mutex<()>;
+#[cfg(feature = "sync")]
+type RefreshLock = Mutex<()>;
+
+/// Upstreams discovered from SRV records of a service
+pub struct SrvUpstreams {
+    name: String,
+    template: Config,
+    resolver: Arc<dyn SrvResolver>,
+    options: DiscoveryOptions,
+    state: Mutex<State>,
+    refreshing: RefreshLock,
+}
+
+impl SrvUpstreams {
+    /// Upstreams of service `name`, sharing the rest of `template` (password, encryption key,
+    /// timeouts, fallback...)
+    pub fn new(
+        name: &str,
+        template: Config,
+        resolver: Arc<dyn SrvResolver>,
+        options: DiscoveryOptions,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            template,
+            resolver,
+            options,
+            state: Mutex::new(State::default()),
+            refreshing: RefreshLock::default(),
+        }
+    }
+
+    /// Upstreams of service `name` resolved with the system DNS configuration
+    #[cfg(all(feature = "srv", feature = "async"))]
+    pub fn from_dns(name: &str, template: Config) -> Result<Self, RspamdError> {
+        Ok(Self::new(
+            name,
+            template,
+            Arc::new(DnsResolver::from_system_conf()?),
+            DiscoveryOptions::default(),
+        ))
+    }
+
+    /// Service name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether records have expired, and whether there are no upstreams yet
+    fn status(&self) -> (bool, bool) {
+        let state = self.state();
+        (
+            state.expires_at.is_none_or(|at| Instant::now() >= at),
+            state.upstreams.is_empty(),
+        )
+    }
+
+    #[cfg(feature = "async")]
+    async fn lock_refresh(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.refreshing.lock().await
+    }
+
+    #[cfg(feature = "sync")]
+    fn lock_refresh(&self) -> MutexGuard<'_, ()> {
+        self.refreshing.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[cfg(feature = "async")]
+    fn try_lock_refresh(&self) -> Option<tokio::sync::MutexGuard<'_, ()>> {
+        self.refreshing.try_lock().ok()
+    }
+
+    #[cfg(feature = "sync")]
+    fn try_lock_refresh(&self) -> Option<MutexGuard<'_, ()>> {
+        match self.refreshing.try_lock() {
+            Ok(guard) => Some(guard),
+            Err(std::sync::TryLockError::Poisoned(e)) => Some(e.into_inner()),
+            Err(std::sync::TryLockError::WouldBlock) => None,
+        }
+    }
+
+    /// Resolve records now, regardless of their TTL; waits for a refresh in progress
+    #[maybe_async::maybe_async]
+    pub async fn refresh(&self) -> Result<(), RspamdError> {
+        let _guard = self.lock_refresh().await;
+        self.resolve().await
+    }
+
+    /// Resolve records and replace upstreams, must be called with the refresh lock held
+    #[maybe_async::maybe_async]
+    async fn resolve(&self) -> Result<(), RspamdError> {
+        let answer = match self.resolver.resolve(&self.name).await {
+            Ok(answer) if answer.targets.is_empty() => Err(RspamdError::DiscoveryError(format!(
+                "No SRV records for {}",
+                self.name
+            ))),
+            result => result,
+        };
+        let mut state = self.state();
+        match answer {
+            Ok(answer) => {
+                let mut upstreams = Vec::with_capacity(answer.targets.len());
+                for target in answer.targets {
+                    let host = target.target.trim_end_matches('.');
+                    if host.is_empty() {
+                        continue;
+                    }
+                    let base_url = format!("{}://{}:{}", self.options.scheme, host, target.port);
+                    upstreams.push(Upstream {
+                        config: Arc::new(self.template.with_base_url(base_url)),
+                        priority: target.priority,
+                        weight: target.weight as i64,
+                        current: 0,
+                    });
+                }
+                upstreams.sort_by(|a, b| {
+                    (a.priority, &a.config.base_url).cmp(&(b.priority, &b.config.base_url))
+                });
+                let ttl = answer.ttl.clamp(
+                    self.options.min_ttl,
+                    self.options.max_ttl.max(self.options.min_ttl),
+                );
+                telemetry::upstreams_discovered(&self.name, upstreams.len(), ttl);
+                state.upstreams = upstreams;
+                state.expires_at = Some(Instant::now() + ttl);
+                Ok(())
+            }
+            Err(e) => {
+                telemetry::discovery_failed(&self.name, &e);
+                state.expires_at = Some(Instant::now() + self.options.min_ttl);
+                Err(e)
+            }
+        }
+    }
+
+    /// Refresh records if they have expired; the previous upstreams are kept if it fails.
+    /// While another caller refreshes records, stale upstreams are served, callers only wait
+    /// if there are no upstreams yet.
+    #[maybe_async::maybe_async]
+    async fn ensure_fresh(&self) -> Result<(), RspamdError> {
+        let (expired, empty) = self.status();
+        if !expired {
+            return Ok(());
+        }
+        let _guard = if empty {
+            self.lock_refresh().await
+        } else {
+            match self.try_lock_refresh() {
+                Some(guard) => guard,
+                None => return Ok(()),
+            }
+        };
+        // Records may have been refreshed while waiting for the lock
+        let (expired, empty) = self.status();
+        if !expired {
+            return Ok(());
+        }
+        match self.resolve().await {
+            Err(e) if empty => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// All upstreams, ordered by priority
+    #[maybe_async::maybe_async]
+    pub async fn upstreams(&self) -> Result<Vec<Arc<Config>>, RspamdError> {
+        self.ensure_fresh().await?;
+        Ok(self
+            .state()
+            .upstreams
+            .iter()
+            .map(|u| u.config.clone())
+            .collect())
+    }
+
+    /// Next upstream to send a request to
+    #[maybe_async::maybe_async]
+    pub async fn select(&self) -> Result<Arc<Config>, RspamdError> {
+        self.ensure_fresh().await?;
+        self.state()
+            .select()
+            .ok_or_else(|| RspamdError::DiscoveryError(format!("No upstreams for {}", self.name)))
+    }
+
+    /// Scan a message on the next upstream
+    #[maybe_async::maybe_async]
+    pub async fn scan<B: AsRef<[u8]> + Send>(
+        &self,
+        body: B,
+        envelope: EnvelopeData,
+    ) -> Result<RspamdScanReply, RspamdError> {
+        let config = self.select().await?;
+        scan(config.as_ref(), body, envelope).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const SERVICE: &str = "_rspamd._tcp.example.com";
+
+    #[cfg(feature = "async")]
+    async fn sleep(duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+
+    #[cfg(feature = "sync")]
+    fn sleep(duration: Duration) {
+        std::thread::sleep(duration)
+    }
+
+    /// Resolver counting lookups, each one taking some time
+    #[derive(Default)]
+    struct SlowResolver {
+        records: StaticResolver,
+        lookups: AtomicUsize,
+    }
+
+    #[maybe_async::maybe_async]
+    impl SrvResolver for SlowResolver {
+        async fn resolve(&self, name: &str) -> Result<SrvAnswer, RspamdError> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            sleep(Duration::from_millis(30)).await;
+            self.records.resolve(name).await
+        }
+    }
+
+    /// Select upstreams from several tasks at once
+    #[cfg(feature = "async")]
+    async fn select_concurrently(upstreams: &SrvUpstreams, n: usize) -> Vec<String> {
+        futures::future::join_all((0..n).map(|_| upstreams.select()))
+            .await
+            .into_iter()
+            .map(|config| config.unwrap().base_url.clone())
+            .collect()
+    }
+
+    #[cfg(feature = "sync")]
+    fn select_concurrently(upstreams: &SrvUpstreams, n: usize) -> Vec<String> {
+        std::thread::scope(|scope| {
+            let handles = (0..n)
+                .map(|_| scope.spawn(|| upstreams.select()))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap().unwrap().base_url.clone())
+                .collect()
+        })
+    }
+
+    fn target(priority: u16, weight: u16, target: &str) -> SrvTarget {
+        SrvTarget {
+            priority,
+            weight,
+            port: 11333,
+            target: target.to_string(),
+        }
+    }
+
+    fn answer(targets: Vec<SrvTarget>, ttl: Duration) -> SrvAnswer {
+        SrvAnswer { targets, ttl }
+    }
+
+    fn discovery(resolver: Arc<dyn SrvResolver>) -> SrvUpstreams {
+        let template = Config::builder()
+            .base_url("http://localhost:11333".to_string())
+            .build();
+        let options = DiscoveryOptions::builder()
+            .min_ttl(Duration::from_millis(50))
+            .build();
+        SrvUpstreams::new(SERVICE, template, resolver, options)
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_weighted_selection() {
+        let resolver = Arc::new(StaticResolver::new());
+        resolver.set(
+            SERVICE,
+            answer(
+                vec![
+                    target(10, 3, "a.example.com."),
+                    target(10, 1, "b.example.com."),
+                    target(20, 100, "backup.example.com."),
+                ],
+                Duration::from_secs(60),
+            ),
+        );
+        let upstreams = discovery(resolver);
+        let urls = upstreams.upstreams().await.unwrap();
+        let urls = urls.iter().map(|c| c.base_url.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            vec![
+                "http://a.example.com:11333",
+                "http://b.example.com:11333",
+                "http://backup.example.com:11333"
+            ]
+        );
+
+        let mut counts = HashMap::new();
+        for _ in 0..8 {
+            let config = upstreams.select().await.unwrap();
+            *counts.entry(config.base_url.clone()).or_insert(0) += 1;
+        }
+        assert_eq!(counts["http://a.example.com:11333"], 6);
+        assert_eq!(counts["http://b.example.com:11333"], 2);
+        assert!(!counts.contains_key("http://backup.example.com:11333"));
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_unavailable_targets() {
+        let resolver = Arc::new(StaticResolver::new());
+        resolver.set(
+            SERVICE,
+            answer(
+                vec![target(0, 0, "."), target(10, 0, "a.example.com.")],
+                Duration::ZERO,
+            ),
+        );
+        let upstreams = discovery(resolver.clone());
+        let urls = upstreams.upstreams().await.unwrap();
+        assert_eq!(urls.len(), 1);
+        assert_eq!(urls[0].base_url, "http://a.example.com:11333");
+
+        // The service is declared unavailable, stale upstreams are not used
+        resolver.set(SERVICE, answer(vec![target(0, 0, ".")], Duration::ZERO));
+        sleep(Duration::from_millis(60)).await;
+        let urls = upstreams.upstreams().await.unwrap();
+        assert!(urls.is_empty());
+        let e = upstreams.select().await.err().unwrap();
+        assert_eq!(e.kind(), "discovery");
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_template_password() {
+        let resolver = Arc::new(StaticResolver::new());
+        resolver.set(
+            SERVICE,
+            answer(
+                vec![target(0, 0, "a.example.com.")],
+                Duration::from_secs(60),
+            ),
+        );
+        let template = Config::builder()
+            .base_url("http://:secret@localhost:11333".to_string())
+            .build();
+        let upstreams = SrvUpstreams::new(SERVICE, template, resolver, DiscoveryOptions::default());
+        let config = upstreams.select().await.unwrap();
+        assert_eq!(config.base_url, "http://a.example.com:11333");
+        assert_eq!(config.password.as_deref(), Some("secret"));
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_refresh_on_ttl() {
+        let resolver = Arc::new(StaticResolver::new());
+        let upstreams = discovery(resolver.clone());
+        let e = upstreams.select().await.err().unwrap();
+        assert_eq!(e.kind(), "discovery");
+
+        resolver.set(
+            SERVICE,
+            answer(vec![target(0, 0, "a.example.com.")], Duration::ZERO),
+        );
+        // The failed resolution is retried after `min_ttl`
+        sleep(Duration::from_millis(60)).await;
+        let config = upstreams.select().await.unwrap();
+        assert_eq!(config.base_url, "http://a.example.com:11333");
+
+        resolver.set(
+            SERVICE,
+            answer(vec![target(0, 0, "b.example.com.")], Duration::ZERO),
+        );
+        let config = upstreams.select().await.unwrap();
+        assert_eq!(config.base_url, "http://a.example.com:11333");
+        sleep(Duration::from_millis(60)).await;
+        let config = upstreams.select().await.unwrap();
+        assert_eq!(config.base_url, "http://b.example.com:11333");
+
+        // Stale upstreams are kept if the service can no longer be resolved
+        resolver.remove(SERVICE);
+        sleep(Duration::from_millis(60)).await;
+        let config = upstreams.select().await.unwrap();
+        assert_eq!(config.base_url, "http://b.example.com:11333");
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_single_flight_refresh() {
+        let resolver = Arc::new(SlowResolver::default());
+        resolver.records.set(
+            SERVICE,
+            answer(vec![target(0, 0, "a.example.com.")], Duration::ZERO),
+        );
+        let upstreams = discovery(resolver.clone());
+
+        // Callers wait for the first resolution
+        let urls = select_concurrently(&upstreams, 8).await;
+        assert!(urls.iter().all(|url| url == "http://a.example.com:11333"));
+        assert_eq!(resolver.lookups.load(Ordering::SeqCst), 1);
+
+        // Stale upstreams are served while records are refreshed
+        resolver.records.set(
+            SERVICE,
+            answer(vec![target(0, 0, "b.example.com.")], Duration::ZERO),
+        );
+        sleep(Duration::from_millis(60)).await;
+        let urls = select_concurrently(&upstreams, 8).await;
+        assert_eq!(resolver.lookups.load(Ordering::SeqCst), 2);
+        assert!(urls.contains(&"http://a.example.com:11333".to_string()));
+        let config = upstreams.select().await.unwrap();
+        assert_eq!(config.base_url, "http://b.example.com:11333");
+    }
+}
@@ -0,0 +1,215 @@
+//! Background health checking of Rspamd servers with `/ping`.
+//!
+//! A `HealthChecker` pings each configured server periodically from a tokio task and keeps
+//! the current state, which can be queried e.g. by a load balancer or a readiness endpoint.
+
+use crate::backend::async_client::ping_async;
+use crate::config::Config;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use typed_builder::TypedBuilder;
+
+/// Health checking options
+#[derive(TypedBuilder, Debug, Clone, PartialEq)]
+pub struct HealthCheckOptions {
+    /// Interval between pings of each server
+    #[builder(default = Duration::from_secs(10))]
+    pub interval: Duration,
+
+    /// Number of consecutive failed pings before a server is marked as unhealthy
+    #[builder(default = 2)]
+    pub failure_threshold: u32,
+
+    /// Number of consecutive successful pings before an unhealthy server is marked as healthy
+    #[builder(default = 1)]
+    pub recovery_threshold: u32,
+}
+
+impl Default for HealthCheckOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Current health of a server
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerHealth {
+    /// Server URL as configured
+    pub base_url: String,
+    /// Servers are considered healthy until the first check proves otherwise
+    pub healthy: bool,
+    /// Time of the last check
+    pub last_check: Option<Instant>,
+    /// Round-trip time of the last successful ping
+    pub last_rtt: Option<Duration>,
+    /// Error of the last failed ping
+    pub last_error: Option<String>,
+    /// Number of consecutive failed pings
+    pub consecutive_failures: u32,
+    /// Number of consecutive successful pings
+    pub consecutive_successes: u32,
+}
+
+impl ServerHealth {
+    fn new(base_url: String) -> Self {
+        Self {
+            base_url,
+            healthy: true,
+            last_check: None,
+            last_rtt: None,
+            last_error: None,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+        }
+    }
+
+    /// Update state with a ping result
+    fn record(&mut self, result: Result<Duration, String>, options: &HealthCheckOptions) {
+        self.last_check = Some(Instant::now());
+        match result {
+            Ok(rtt) => {
+                self.last_rtt = Some(rtt);
+                self.last_error = None;
+                self.consecutive_failures = 0;
+                self.consecutive_successes = self.consecutive_successes.saturating_add(1);
+                if !self.healthy && self.consecutive_successes >= options.recovery_threshold {
+                    self.healthy = true;
+                }
+            }
+            Err(e) => {
+                self.last_error = Some(e);
+                self.consecutive_successes = 0;
+                self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+                if self.healthy && self.consecutive_failures >= options.failure_threshold {
+                    self.healthy = false;
+                }
+            }
+        }
+    }
+}
+
+/// Periodically pings servers in background, the task is stopped when the checker is dropped
+pub struct HealthChecker {
+    state: Arc<RwLock<Vec<ServerHealth>>>,
+    task: JoinHandle<()>,
+}
+
+impl HealthChecker {
+    /// Start health checking of `servers`, must be called within a tokio runtime
+    pub fn spawn(servers: Vec<Arc<Config>>, options: HealthCheckOptions) -> Self {
+        let state = Arc::new(RwLock::new(
+            servers
+                .iter()
+                .map(|config| ServerHealth::new(config.base_url.clone()))
+                .collect::<Vec<_>>(),
+        ));
+        let task_state = state.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(options.interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let pings = servers.iter().map(|config| ping_async(config));
+                let results = futures::future::join_all(pings).await;
+                let mut state = task_state.write().unwrap_or_else(|e| e.into_inner());
+                for (server, result) in state.iter_mut().zip(results) {
+                    server.record(result.map_err(|e| e.to_string()), &options);
+                }
+            }
+        });
+
+        Self { state, task }
+    }
+
+    /// Snapshot of the state of all servers, in the order they were configured
+    pub fn state(&self) -> Vec<ServerHealth> {
+        self.state.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Whether the server with this URL is healthy, `None` if it is not checked
+    pub fn is_healthy(&self, base_url: &str) -> Option<bool> {
+        self.state
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .find(|s| s.base_url == base_url)
+            .map(|s| s.healthy)
+    }
+
+    /// URLs of healthy servers
+    pub fn healthy_servers(&self) -> Vec<String> {
+        self.state
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|s| s.healthy)
+            .map(|s| s.base_url.clone())
+            .collect()
+    }
+
+    /// Whether at least one server is healthy, suitable for a readiness check
+    pub fn is_ready(&self) -> bool {
+        self.state
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .any(|s| s.healthy)
+    }
+}
+
+impl Drop for HealthChecker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thresholds() {
+        let options = HealthCheckOptions::builder()
+            .failure_threshold(2)
+            .recovery_threshold(2)
+            .build();
+        let mut health = ServerHealth::new("http://localhost:11333".to_string());
+
+        health.record(Err("refused".to_string()), &options);
+        assert!(health.healthy);
+        health.record(Err("refused".to_string()), &options);
+        assert!(!health.healthy);
+        health.record(Ok(Duration::from_millis(1)), &options);
+        assert!(!health.healthy);
+        health.record(Ok(Duration::from_millis(1)), &options);
+        assert!(health.healthy);
+        assert_eq!(health.last_error, None);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_server() {
+        let config = Arc::new(
+            Config::builder()
+                .base_url("http://127.0.0.1:1".to_string())
+                .retries(1)
+                .build(),
+        );
+        let checker = HealthChecker::spawn(
+            vec![config],
+            HealthCheckOptions::builder()
+                .interval(Duration::from_millis(10))
+                .failure_threshold(1)
+                .build(),
+        );
+        for _ in 0..100 {
+            if !checker.is_ready() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(checker.is_healthy("http://127.0.0.1:1"), Some(false));
+        assert!(checker.healthy_servers().is_empty());
+        assert!(checker.state()[0].last_error.is_some());
+    }
+}
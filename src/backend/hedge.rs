@@ -0,0 +1,262 @@
+//! Hedged scans over several upstreams to cut tail latency.
+//!
+//! A scan is sent to one upstream (chosen round-robin); if no reply arrives within `delay`,
+//! the same scan is sent to the next upstream and the first successful reply is returned,
+//! the other request is cancelled. A scan that fails before `delay` is hedged immediately.
+//! Hedges are capped to `max_hedge_ratio` of scans, so a slow cluster is not doubly loaded.
+
+use crate::backend::async_client::scan_async;
+use crate::config::{Config, EnvelopeData};
+use crate::error::RspamdError;
+use crate::protocol::RspamdScanReply;
+use crate::telemetry;
+use futures::future::{select, Either};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use typed_builder::TypedBuilder;
+
+/// Hedging options
+#[derive(TypedBuilder, Debug, Clone, PartialEq)]
+pub struct HedgingOptions {
+    /// Time to wait for a reply before the scan is sent to another upstream,
+    /// usually about the p95 latency of scans
+    #[builder(default = Duration::from_millis(500))]
+    pub delay: Duration,
+
+    /// Maximum ratio of hedged scans (from 0.0 to 1.0)
+    #[builder(default = 0.1)]
+    pub max_hedge_ratio: f64,
+}
+
+impl Default for HedgingOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Counters of a hedged scanner
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HedgeStats {
+    /// Scans requested
+    pub scans: u64,
+    /// Scans that have been sent to a second upstream
+    pub hedged: u64,
+    /// Hedged scans where the reply of the second upstream has been used
+    pub hedge_wins: u64,
+}
+
+type ScanResult = Result<RspamdScanReply, RspamdError>;
+
+/// Fallback verdicts are not successful replies, another upstream may still reply
+fn is_success(result: &ScanResult) -> bool {
+    matches!(result, Ok(reply) if !reply.is_fallback())
+}
+
+/// Scans messages with hedging over several upstreams
+pub struct HedgedScanner {
+    upstreams: Vec<Arc<Config>>,
+    options: HedgingOptions,
+    next: AtomicUsize,
+    scans: AtomicU64,
+    hedged: AtomicU64,
+    hedge_wins: AtomicU64,
+}
+
+impl HedgedScanner {
+    /// Create a scanner over `upstreams`, at least one is required
+    pub fn new(upstreams: Vec<Arc<Config>>, options: HedgingOptions) -> Result<Self, RspamdError> {
+        if upstreams.is_empty() {
+            return Err(RspamdError::ConfigError(
+                "Hedging requires at least one upstream".to_string(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&options.max_hedge_ratio) {
+            return Err(RspamdError::ConfigError(format!(
+                "Invalid hedge ratio: {}",
+                options.max_hedge_ratio
+            )));
+        }
+        Ok(Self {
+            upstreams,
+            options,
+            next: AtomicUsize::new(0),
+            scans: AtomicU64::new(0),
+            hedged: AtomicU64::new(0),
+            hedge_wins: AtomicU64::new(0),
+        })
+    }
+
+    /// Upstreams of the scanner
+    pub fn upstreams(&self) -> &[Arc<Config>] {
+        &self.upstreams
+    }
+
+    /// Counters of scans and hedges
+    pub fn stats(&self) -> HedgeStats {
+        HedgeStats {
+            scans: self.scans.load(Ordering::Relaxed),
+            hedged: self.hedged.load(Ordering::Relaxed),
+            hedge_wins: self.hedge_wins.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Take a hedge from the budget if the ratio allows it
+    fn try_hedge(&self, scans: u64) -> bool {
+        self.hedged
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |hedged| {
+                ((hedged as f64) < self.options.max_hedge_ratio * scans as f64)
+                    .then_some(hedged + 1)
+            })
+            .is_ok()
+    }
+
+    /// Scan a message, hedging the request if the first upstream is slow or fails
+    pub async fn scan<B: AsRef<[u8]> + Send>(
+        &self,
+        body: B,
+        envelope: EnvelopeData,
+    ) -> Result<RspamdScanReply, RspamdError> {
+        let body = body.as_ref();
+        let scans = self.scans.fetch_add(1, Ordering::Relaxed) + 1;
+        let first = self.next.fetch_add(1, Ordering::Relaxed) % self.upstreams.len();
+        let primary = &self.upstreams[first];
+        let mut primary_scan = Box::pin(scan_async(primary.as_ref(), body, envelope.clone()));
+
+        let early = match tokio::time::timeout(self.options.delay, &mut primary_scan).await {
+            Ok(result) if is_success(&result) => return result,
+            Ok(result) => Some(result),
+            Err(_) => None,
+        };
+        if self.upstreams.len() < 2 || !self.try_hedge(scans) {
+            return match early {
+                Some(result) => result,
+                None => primary_scan.await,
+            };
+        }
+
+        let hedge = &self.upstreams[(first + 1) % self.upstreams.len()];
+        telemetry::hedge_sent(&primary.base_url, &hedge.base_url, early.is_some());
+        let hedge_scan = Box::pin(scan_async(hedge.as_ref(), body, envelope));
+        let (result, from_hedge) = match early {
+            Some(_) => (hedge_scan.await, true),
+            None => match select(primary_scan, hedge_scan).await {
+                Either::Left((result, _)) if is_success(&result) => (result, false),
+                Either::Left((_, hedge_scan)) => (hedge_scan.await, true),
+                Either::Right((result, _)) if is_success(&result) => (result, true),
+                Either::Right((_, primary_scan)) => (primary_scan.await, false),
+            },
+        };
+        if from_hedge && is_success(&result) {
+            self.hedge_wins.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve scans with a fixed action after `delay`
+    async fn serve(action: &'static str, delay: Duration) -> Arc<Config> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 4096];
+                    loop {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                        let mut hdrs = [httparse::EMPTY_HEADER; 64];
+                        let mut parsed = httparse::Request::new(&mut hdrs);
+                        if let Ok(httparse::Status::Complete(offset)) = parsed.parse(&request) {
+                            let length = parsed
+                                .headers
+                                .iter()
+                                .find(|h| h.name.eq_ignore_ascii_case("Content-Length"))
+                                .map_or(0, |h| {
+                                    std::str::from_utf8(h.value).unwrap().parse().unwrap()
+                                });
+                            if request.len() >= offset + length {
+                                break;
+                            }
+                        }
+                    }
+                    tokio::time::sleep(delay).await;
+                    let body = format!(r#"{{"action":"{}","score":1.0}}"#, action);
+                    let reply = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(reply.as_bytes()).await;
+                });
+            }
+        });
+        Arc::new(Config::builder().base_url(base_url).zstd(false).build())
+    }
+
+    #[tokio::test]
+    async fn test_hedge_slow_upstream() {
+        let slow = serve("reject", Duration::from_secs(2)).await;
+        let fast = serve("no action", Duration::ZERO).await;
+        let options = HedgingOptions::builder()
+            .delay(Duration::from_millis(50))
+            .max_hedge_ratio(0.5)
+            .build();
+        let scanner = HedgedScanner::new(vec![slow, fast], options).unwrap();
+
+        let started = Instant::now();
+        let reply = scanner.scan("Test", Default::default()).await.unwrap();
+        assert_eq!(reply.action, "no action");
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            scanner.stats(),
+            HedgeStats {
+                scans: 1,
+                hedged: 1,
+                hedge_wins: 1
+            }
+        );
+
+        // The fast upstream is the primary for the second scan
+        let reply = scanner.scan("Test", Default::default()).await.unwrap();
+        assert_eq!(reply.action, "no action");
+        assert_eq!(scanner.stats().hedged, 1);
+    }
+
+    #[tokio::test]
+    async fn test_hedge_ratio_and_failures() {
+        let slow = serve("reject", Duration::from_millis(200)).await;
+        let down = Arc::new(
+            Config::builder()
+                .base_url("http://127.0.0.1:1".to_string())
+                .build(),
+        );
+        let options = HedgingOptions::builder()
+            .delay(Duration::from_millis(20))
+            .max_hedge_ratio(0.0)
+            .build();
+        let scanner = HedgedScanner::new(vec![slow.clone(), down.clone()], options).unwrap();
+        // No hedge budget: the slow reply is awaited
+        let reply = scanner.scan("Test", Default::default()).await.unwrap();
+        assert_eq!(reply.action, "reject");
+        assert_eq!(scanner.stats().hedged, 0);
+
+        // A failed primary is hedged immediately
+        let options = HedgingOptions::builder().max_hedge_ratio(1.0).build();
+        let scanner = HedgedScanner::new(vec![down, slow], options).unwrap();
+        let reply = scanner.scan("Test", Default::default()).await.unwrap();
+        assert_eq!(reply.action, "reject");
+        assert_eq!(scanner.stats().hedge_wins, 1);
+
+        assert!(HedgedScanner::new(Vec::new(), HedgingOptions::default()).is_err());
+    }
+}
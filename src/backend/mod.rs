@@ -1,7 +1,278 @@
 #[cfg(feature = "async")]
 pub mod async_client;
+pub mod cache;
+pub mod circuit_breaker;
+pub mod cluster;
+pub mod controller;
+mod decode;
+pub mod discovery;
+#[cfg(feature = "async")]
+pub mod health;
+#[cfg(feature = "async")]
+pub mod hedge;
+pub mod rate_limit;
+pub mod shadow;
 #[cfg(feature = "sync")]
 pub mod sync_client;
 pub mod traits;
 
 pub use traits::*;
+
+pub(crate) use decode::decode_response;
+
+use crate::config::{CompressionSettings, Config};
+use crate::error::RspamdError;
+use crate::protocol::commands::RspamdEndpoint;
+use crate::protocol::RspamdScanReply;
+use crate::telemetry;
+use std::time::Instant;
+
+/// Compress request body with zstd, using the configured level and dictionary
+pub(crate) fn zstd_compress(
+    body: &[u8],
+    settings: &CompressionSettings,
+) -> Result<Vec<u8>, RspamdError> {
+    let started = Instant::now();
+    let compressed = match settings.dictionary.as_ref() {
+        Some(dictionary) => {
+            zstd::bulk::Compressor::with_dictionary(settings.level, dictionary.data())?
+                .compress(body)?
+        }
+        None => zstd::encode_all(body, settings.level)?,
+    };
+    telemetry::compressed(body.len(), compressed.len(), started.elapsed());
+    Ok(compressed)
+}
+
+/// Check the `/ping` reply, both normal and controller workers reply with `pong`
+pub(crate) fn check_pong(body: &[u8]) -> Result<(), RspamdError> {
+    if body.trim_ascii() == b"pong" {
+        Ok(())
+    } else {
+        Err(RspamdError::HttpError(format!(
+            "Unexpected ping reply: {}",
+            String::from_utf8_lossy(&body[..body.len().min(64)])
+        )))
+    }
+}
+
+/// Error for a reply with a non successful status, controller replies contain `{"error": "..."}`
+pub(crate) fn status_error(endpoint: &RspamdEndpoint, status: u16, body: &[u8]) -> RspamdError {
+    let message = serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|v| v.get("error").and_then(|e| e.as_str()).map(str::to_string));
+
+    match status {
+        401 | 403 if endpoint.privileged => RspamdError::AuthorizationError(format!(
+            "{} requires enable_password: {}",
+            endpoint.url,
+            message.as_deref().unwrap_or("forbidden")
+        )),
+        401 | 403 => RspamdError::AuthorizationError(format!(
+            "{}: {}",
+            endpoint.url,
+            message.as_deref().unwrap_or("forbidden")
+        )),
+        404 => RspamdError::ServerError {
+            status,
+            message: message.unwrap_or_else(|| format!("{} not found", endpoint.url)),
+        },
+        _ => match message {
+            Some(message) => RspamdError::ServerError { status, message },
+            None => RspamdError::HttpError(format!("Status: {}", status)),
+        },
+    }
+}
+
+/// Parse a scan reply and report parse failures, like transport failures, as failed requests
+pub(crate) fn parse_scan_reply(
+    config: &Config,
+    command: &str,
+    message_offset: Option<&[u8]>,
+    body: &[u8],
+) -> Result<RspamdScanReply, RspamdError> {
+    let parse_started = Instant::now();
+    match split_scan_reply(message_offset, body) {
+        Ok(reply) => {
+            telemetry::reply_parsed(
+                reply.action.as_str(),
+                reply.score,
+                body.len(),
+                parse_started.elapsed(),
+            );
+            Ok(reply)
+        }
+        Err(e) => {
+            telemetry::request_failed(command, &config.base_url, &e);
+            Err(e)
+        }
+    }
+}
+
+/// With the `Message-Offset` header (body_block) the JSON reply is followed by the rewritten body
+fn split_scan_reply(
+    message_offset: Option<&[u8]>,
+    body: &[u8],
+) -> Result<RspamdScanReply, RspamdError> {
+    let offset = match message_offset {
+        Some(value) => std::str::from_utf8(value)
+            .map_err(|e| RspamdError::HttpError(format!("Invalid Message-Offset header: {}", e)))?
+            .parse::<usize>()
+            .map(Some)
+            .map_err(|e| RspamdError::HttpError(format!("Invalid Message-Offset value: {}", e)))?,
+        None => None,
+    };
+    match offset {
+        // Split body into JSON part and rewritten body part
+        Some(offset) if offset < body.len() => {
+            let mut reply = serde_json::from_slice::<RspamdScanReply>(&body[..offset])?;
+            reply.rewritten_body = Some(body[offset..].to_vec());
+            Ok(reply)
+        }
+        // No Message-Offset header or offset out of bounds, parse entire body as JSON
+        _ => Ok(serde_json::from_slice::<RspamdScanReply>(body)?),
+    }
+}
+
+/// Replace a transport error with the fallback verdict, if a policy is configured
+pub(crate) fn fallback_reply(
+    config: &Config,
+    error: RspamdError,
+) -> Result<RspamdScanReply, RspamdError> {
+    match config.fallback {
+        Some(policy) if error.is_transport() => {
+            telemetry::fallback_used(policy.action(), &error);
+            Ok(RspamdScanReply::fallback(
+                policy.action(),
+                error.to_string(),
+            ))
+        }
+        _ => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::commands::RspamdCommand;
+
+    #[test]
+    fn test_status_error() {
+        let body = br#"{"error":"Unauthorized"}"#;
+        let save = RspamdEndpoint::from_command(RspamdCommand::SaveMap);
+        match status_error(&save, 403, body) {
+            RspamdError::AuthorizationError(e) => assert!(e.contains("enable_password")),
+            e => panic!("unexpected error: {}", e),
+        }
+        // Rspamd checks `enable_password` for learning too
+        for command in [RspamdCommand::Learnspam, RspamdCommand::Learnham] {
+            let learn = RspamdEndpoint::from_command(command);
+            match status_error(&learn, 403, body) {
+                RspamdError::AuthorizationError(e) => assert!(e.contains("enable_password")),
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+        let maps = RspamdEndpoint::from_command(RspamdCommand::Maps);
+        match status_error(&maps, 403, body) {
+            RspamdError::AuthorizationError(e) => assert!(!e.contains("enable_password")),
+            e => panic!("unexpected error: {}", e),
+        }
+        assert!(matches!(
+            status_error(&maps, 500, br#"{"error":"invalid action value"}"#),
+            RspamdError::ServerError { status: 500, .. }
+        ));
+        assert_eq!(
+            status_error(&maps, 500, b"").to_string(),
+            "HTTP request failed: Status: 500"
+        );
+    }
+
+    #[test]
+    fn test_parse_scan_reply() {
+        let config = Config::builder()
+            .base_url("http://localhost:11333".to_string())
+            .build();
+        let json = br#"{"action":"reject","score":15.0}"#;
+        let mut body = json.to_vec();
+        body.extend_from_slice(b"Subject: rewritten\r\n\r\nBody");
+        let offset = json.len().to_string();
+        let reply = parse_scan_reply(&config, "/checkv2", Some(offset.as_bytes()), &body).unwrap();
+        assert_eq!(reply.action, "reject");
+        assert_eq!(
+            reply.rewritten_body.as_deref(),
+            Some(&b"Subject: rewritten\r\n\r\nBody"[..])
+        );
+
+        let e = parse_scan_reply(&config, "/checkv2", Some(b"x"), &body).unwrap_err();
+        assert!(e.to_string().contains("Invalid Message-Offset value"));
+        let e = parse_scan_reply(&config, "/checkv2", None, b"{").unwrap_err();
+        assert_eq!(e.kind(), "serde");
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_fallback_reply() {
+        use crate::config::FallbackPolicy;
+        #[cfg(feature = "async")]
+        use crate::scan_async as scan;
+        #[cfg(feature = "sync")]
+        use crate::scan_sync as scan;
+
+        let mut config = Config::builder()
+            .base_url("http://127.0.0.1:1".to_string())
+            .retries(1)
+            .build();
+        let e = scan(&config, "Test", Default::default()).await.unwrap_err();
+        assert!(e.is_transport(), "unexpected error: {}", e);
+
+        config.fallback = Some(FallbackPolicy::Tempfail);
+        let reply = scan(&config, "Test", Default::default()).await.unwrap();
+        assert!(reply.is_fallback());
+        assert_eq!(reply.action, "soft reject");
+
+        // Server accepts connections but never replies
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        config.base_url = format!("http://{}", listener.local_addr().unwrap());
+        config.timeout = std::time::Duration::from_millis(200);
+        config.fallback = None;
+        let e = scan(&config, "Test", Default::default()).await.unwrap_err();
+        assert_eq!(e.kind(), "timeout");
+        config.fallback = Some(FallbackPolicy::Reject);
+        let reply = scan(&config, "Test", Default::default()).await.unwrap();
+        assert_eq!(reply.action, "reject");
+        assert!(reply.fallback.unwrap().contains("Transport error"));
+    }
+
+    #[test]
+    fn test_zstd_compress_with_dictionary() {
+        use crate::config::ZstdDictionary;
+
+        let samples: Vec<Vec<u8>> = (0..1000)
+            .map(|i| {
+                format!(
+                    "From: user{}@example.com\nTo: rcpt{}@example.org\nSubject: Message {}\n\nHello {}\n",
+                    i, i * 7, i * 13, i * 31
+                )
+                .into_bytes()
+            })
+            .collect();
+        let dictionary =
+            ZstdDictionary::new(zstd::dict::from_samples(&samples, 4096).unwrap()).unwrap();
+        assert_ne!(dictionary.id(), 0);
+
+        let settings = CompressionSettings::builder()
+            .level(3)
+            .dictionary(dictionary.clone())
+            .build();
+        let body = &samples[42];
+        let compressed = zstd_compress(body, &settings).unwrap();
+        let decompressed = zstd::bulk::Decompressor::with_dictionary(dictionary.data())
+            .unwrap()
+            .decompress(&compressed, body.len())
+            .unwrap();
+        assert_eq!(&decompressed, body);
+        assert_eq!(
+            zstd::zstd_safe::get_dict_id_from_frame(&compressed).map(|id| id.get()),
+            Some(dictionary.id())
+        );
+    }
+}
@@ -0,0 +1,392 @@
+//! Client-side rate limiting: a token bucket limiting the rate of requests and a cap on
+//! requests in flight, shared by all requests made with the same `Config`.
+//!
+//! Requests have a priority class (`EnvelopeData::priority`). Batch requests are not sent
+//! while live requests are waiting, and can be restricted to a part of the in-flight capacity
+//! with `batch_max_in_flight`, so that learning jobs or bulk rescans never delay live scans
+//! by more than the requests already in flight.
+
+use crate::config::{Priority, RateLimitSettings};
+use crate::error::RspamdError;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+struct State {
+    tokens: f64,
+    refilled_at: Instant,
+    in_flight: usize,
+    batch_in_flight: usize,
+    live_waiting: usize,
+}
+
+impl State {
+    /// Take a slot if limits allow it, otherwise return how long to wait before trying again
+    /// (`None` means until a request completes)
+    fn try_acquire(
+        &mut self,
+        settings: &RateLimitSettings,
+        priority: Priority,
+    ) -> Result<(), Option<Duration>> {
+        if priority == Priority::Batch
+            && (self.live_waiting > 0
+                || settings
+                    .batch_max_in_flight
+                    .is_some_and(|max| self.batch_in_flight >= max))
+        {
+            return Err(None);
+        }
+        if settings
+            .max_in_flight
+            .is_some_and(|max| self.in_flight >= max)
+        {
+            return Err(None);
+        }
+        if let Some(rps) = settings.requests_per_second {
+            let now = Instant::now();
+            let burst = settings.burst.max(1) as f64;
+            self.tokens =
+                (self.tokens + now.duration_since(self.refilled_at).as_secs_f64() * rps).min(burst);
+            self.refilled_at = now;
+            if self.tokens < 1.0 {
+                return Err(Some(Duration::from_secs_f64((1.0 - self.tokens) / rps)));
+            }
+            self.tokens -= 1.0;
+        }
+        self.in_flight += 1;
+        if priority == Priority::Batch {
+            self.batch_in_flight += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Time to wait for the next attempt, bounded by the deadline
+fn wait_time(wait: Option<Duration>, deadline: Option<Instant>) -> Option<Duration> {
+    let now = Instant::now();
+    match (wait, deadline) {
+        (Some(wait), Some(deadline)) => Some(wait.min(deadline.saturating_duration_since(now))),
+        (None, Some(deadline)) => Some(deadline.saturating_duration_since(now)),
+        (wait, None) => wait,
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    #[cfg(feature = "async")]
+    released: tokio::sync::Notify,
+    #[cfg(feature = "sync")]
+    released: std::sync::Condvar,
+}
+
+/// Rate limiter, clones share the same limits and counters
+#[derive(Clone)]
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    shared: Arc<Shared>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        let state = State {
+            tokens: settings.burst.max(1) as f64,
+            refilled_at: Instant::now(),
+            in_flight: 0,
+            batch_in_flight: 0,
+            live_waiting: 0,
+        };
+        Self {
+            settings,
+            shared: Arc::new(Shared {
+                state: Mutex::new(state),
+                released: Default::default(),
+            }),
+        }
+    }
+
+    pub fn settings(&self) -> &RateLimitSettings {
+        &self.settings
+    }
+
+    /// Number of requests in flight
+    pub fn in_flight(&self) -> usize {
+        self.state().in_flight
+    }
+
+    /// Number of live requests waiting for a slot
+    pub fn live_waiting(&self) -> usize {
+        self.state().live_waiting
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.settings.max_wait.map(|wait| Instant::now() + wait)
+    }
+
+    fn timed_out(&self, deadline: Option<Instant>, priority: Priority) -> Result<(), RspamdError> {
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(RspamdError::RateLimited(format!(
+                "no slot for a {:?} request within {:?}",
+                priority,
+                self.settings.max_wait.unwrap_or_default()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait for a slot, it is released when the returned guard is dropped
+    #[cfg(feature = "async")]
+    pub(crate) async fn acquire(&self, priority: Priority) -> Result<Slot<'_>, RspamdError> {
+        let deadline = self.deadline();
+        let mut waiting = None;
+        loop {
+            let released = self.shared.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            let wait = {
+                let mut state = self.state();
+                match state.try_acquire(&self.settings, priority) {
+                    Ok(()) => return Ok(Slot::new(self, priority)),
+                    Err(wait) => {
+                        if waiting.is_none() {
+                            waiting = Some(Waiting::new(self, &mut state, priority));
+                        }
+                        wait
+                    }
+                }
+            };
+            self.timed_out(deadline, priority)?;
+            match wait_time(wait, deadline) {
+                Some(wait) => {
+                    let _ = tokio::time::timeout(wait, released).await;
+                }
+                None => released.await,
+            }
+        }
+    }
+
+    /// Wait for a slot, it is released when the returned guard is dropped
+    #[cfg(feature = "sync")]
+    pub(crate) fn acquire(&self, priority: Priority) -> Result<Slot<'_>, RspamdError> {
+        let deadline = self.deadline();
+        let mut waiting = false;
+        let mut state = self.state();
+        loop {
+            let wait = match state.try_acquire(&self.settings, priority) {
+                Ok(()) => {
+                    if waiting && priority == Priority::Live {
+                        state.live_waiting -= 1;
+                        drop(state);
+                        self.notify();
+                    }
+                    return Ok(Slot::new(self, priority));
+                }
+                Err(wait) => wait,
+            };
+            if !waiting && priority == Priority::Live {
+                state.live_waiting += 1;
+            }
+            waiting = true;
+            if let Err(e) = self.timed_out(deadline, priority) {
+                if priority == Priority::Live {
+                    state.live_waiting -= 1;
+                    drop(state);
+                    self.notify();
+                }
+                return Err(e);
+            }
+            state = match wait_time(wait, deadline) {
+                Some(wait) => {
+                    self.shared
+                        .released
+                        .wait_timeout(state, wait)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self
+                    .shared
+                    .released
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+
+    fn notify(&self) {
+        #[cfg(feature = "async")]
+        self.shared.released.notify_waiters();
+        #[cfg(feature = "sync")]
+        self.shared.released.notify_all();
+    }
+}
+
+/// Live request waiting for a slot in the async client, batch requests wait while it exists
+#[cfg(feature = "async")]
+struct Waiting<'a> {
+    limiter: &'a RateLimiter,
+    priority: Priority,
+}
+
+#[cfg(feature = "async")]
+impl<'a> Waiting<'a> {
+    fn new(limiter: &'a RateLimiter, state: &mut State, priority: Priority) -> Self {
+        if priority == Priority::Live {
+            state.live_waiting += 1;
+        }
+        Self { limiter, priority }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self.priority == Priority::Live {
+            self.limiter.state().live_waiting -= 1;
+            self.limiter.notify();
+        }
+    }
+}
+
+/// Slot of a request in flight
+pub(crate) struct Slot<'a> {
+    limiter: &'a RateLimiter,
+    priority: Priority,
+}
+
+impl<'a> Slot<'a> {
+    fn new(limiter: &'a RateLimiter, priority: Priority) -> Self {
+        Self { limiter, priority }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        {
+            let mut state = self.limiter.state();
+            state.in_flight -= 1;
+            if self.priority == Priority::Batch {
+                state.batch_in_flight -= 1;
+            }
+        }
+        self.limiter.notify();
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("settings", &self.settings)
+            .field("in_flight", &self.in_flight())
+            .finish()
+    }
+}
+
+/// Counters are not a part of the configuration, limiters with the same settings are equal
+impl PartialEq for RateLimiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.settings == other.settings
+    }
+}
+
+impl<'de> Deserialize<'de> for RateLimiter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        RateLimitSettings::deserialize(deserializer).map(RateLimiter::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_token_bucket() {
+        let limiter = RateLimiter::new(
+            RateLimitSettings::builder()
+                .requests_per_second(20.0)
+                .burst(2)
+                .build(),
+        );
+        let started = Instant::now();
+        for _ in 0..4 {
+            limiter.acquire(Priority::Live).await.unwrap();
+        }
+        // Two requests of the burst are immediate, two more need 50ms each
+        assert!(started.elapsed() >= Duration::from_millis(90));
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_max_wait() {
+        let limiter = RateLimiter::new(
+            RateLimitSettings::builder()
+                .max_in_flight(1)
+                .max_wait(Duration::from_millis(20))
+                .build(),
+        );
+        let slot = limiter.acquire(Priority::Live).await.unwrap();
+        let e = limiter.acquire(Priority::Live).await.err().unwrap();
+        assert_eq!(e.kind(), "rate_limited");
+        assert_eq!(limiter.live_waiting(), 0);
+        drop(slot);
+        let slot = limiter.acquire(Priority::Batch).await;
+        assert!(slot.is_ok());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_live_preempts_batch() {
+        let limiter = RateLimiter::new(RateLimitSettings::builder().max_in_flight(1).build());
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let slot = limiter.acquire(Priority::Live).await.unwrap();
+
+        let mut tasks = Vec::new();
+        for priority in [Priority::Batch, Priority::Live] {
+            let (limiter, order) = (limiter.clone(), order.clone());
+            tasks.push(tokio::spawn(async move {
+                let _slot = limiter.acquire(priority).await.unwrap();
+                order.lock().unwrap().push(priority);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(limiter.live_waiting(), 1);
+        drop(slot);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec![Priority::Live, Priority::Batch]
+        );
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn test_live_preempts_batch() {
+        let limiter = RateLimiter::new(RateLimitSettings::builder().max_in_flight(1).build());
+        let order = Mutex::new(Vec::new());
+        let slot = limiter.acquire(Priority::Live).unwrap();
+
+        std::thread::scope(|scope| {
+            for priority in [Priority::Batch, Priority::Live] {
+                let (limiter, order) = (&limiter, &order);
+                scope.spawn(move || {
+                    let _slot = limiter.acquire(priority).unwrap();
+                    order.lock().unwrap().push(priority);
+                    std::thread::sleep(Duration::from_millis(10));
+                });
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            assert_eq!(limiter.live_waiting(), 1);
+            drop(slot);
+        });
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec![Priority::Live, Priority::Batch]
+        );
+    }
+}
@@ -0,0 +1,321 @@
+//! Shadow scanning: mirrors a sample of scans to a secondary Rspamd (e.g. a canary running a new
+//! version or rule set) and reports differences between replies, without affecting the primary scan.
+//!
+//! Shadow scans are run in background (a tokio task for the async client, a thread for the sync one),
+//! the primary reply is returned as soon as it is available. Fallback verdicts are not real replies,
+//! so nothing is reported when either the primary or the shadow reply is a fallback one.
+
+use crate::config::{Config, EnvelopeData};
+use crate::error::RspamdError;
+use crate::protocol::diff::ReplyDiff;
+use crate::protocol::RspamdScanReply;
+use bytes::Bytes;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "async")]
+use crate::backend::async_client::scan_async as scan;
+#[cfg(feature = "sync")]
+use crate::backend::sync_client::scan_sync as scan;
+
+/// Result of a shadow scan
+#[derive(Debug)]
+pub struct ShadowReport {
+    /// Primary reply
+    pub primary: RspamdScanReply,
+    /// Difference between primary and shadow replies, or an error from the shadow server
+    pub result: Result<ReplyDiff, RspamdError>,
+    /// Time taken by the shadow scan
+    pub elapsed: Duration,
+}
+
+/// Callback receiving shadow scan results
+pub type ShadowCallback = Arc<dyn Fn(ShadowReport) + Send + Sync>;
+
+/// Mirrors a sample of scans to a secondary server
+pub struct ShadowScanner {
+    config: Arc<Config>,
+    sample_rate: f64,
+    max_in_flight: usize,
+    callback: ShadowCallback,
+    seen: AtomicU64,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Decrements in-flight counter when a shadow scan is finished
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl ShadowScanner {
+    /// Create a shadow scanner for the secondary server `config`, mirroring `sample_rate`
+    /// (from 0.0 to 1.0) of scans; `callback` is called for each finished shadow scan
+    pub fn new<F>(config: Arc<Config>, sample_rate: f64, callback: F) -> Self
+    where
+        F: Fn(ShadowReport) + Send + Sync + 'static,
+    {
+        Self {
+            config,
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            max_in_flight: 64,
+            callback: Arc::new(callback),
+            seen: AtomicU64::new(0),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Maximum number of concurrent shadow scans, further scans are not mirrored (default: 64)
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// Number of shadow scans in progress
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    /// Deterministic sampling: exactly `sample_rate` of calls return true in the long run
+    fn should_sample(&self) -> bool {
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.sample_rate).floor() > (n * self.sample_rate).floor()
+    }
+
+    fn acquire(&self) -> Option<InFlightGuard> {
+        let acquired = self
+            .in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |cur| {
+                (cur < self.max_in_flight).then_some(cur + 1)
+            })
+            .is_ok();
+        acquired.then(|| InFlightGuard(self.in_flight.clone()))
+    }
+
+    /// Scan with the primary config and mirror the scan to the shadow server if sampled.
+    /// Shadow errors are reported to the callback only and never affect the returned reply.
+    #[maybe_async::maybe_async]
+    pub async fn scan<B: AsRef<[u8]> + Send>(
+        &self,
+        primary: &Config,
+        body: B,
+        envelope_data: EnvelopeData,
+    ) -> Result<RspamdScanReply, RspamdError> {
+        let mirror = if self.should_sample() {
+            self.acquire().map(|guard| {
+                (
+                    guard,
+                    Bytes::copy_from_slice(body.as_ref()),
+                    envelope_data.clone(),
+                )
+            })
+        } else {
+            None
+        };
+
+        let reply = scan(primary, body, envelope_data).await?;
+
+        if let Some((guard, body, envelope_data)) = mirror {
+            if !reply.is_fallback() {
+                self.spawn_shadow(guard, body, envelope_data, reply.clone());
+            }
+        }
+
+        Ok(reply)
+    }
+
+    #[cfg(feature = "async")]
+    fn spawn_shadow(
+        &self,
+        guard: InFlightGuard,
+        body: Bytes,
+        envelope_data: EnvelopeData,
+        primary: RspamdScanReply,
+    ) {
+        let config = self.config.clone();
+        let callback = self.callback.clone();
+        // Shadow scan cannot be done outside of a runtime, just skip it then
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                let _guard = guard;
+                let started = Instant::now();
+                let result = scan(&config, body, envelope_data).await;
+                report(&callback, primary, result, started.elapsed());
+            });
+        }
+    }
+
+    #[cfg(feature = "sync")]
+    fn spawn_shadow(
+        &self,
+        guard: InFlightGuard,
+        body: Bytes,
+        envelope_data: EnvelopeData,
+        primary: RspamdScanReply,
+    ) {
+        let config = self.config.clone();
+        let callback = self.callback.clone();
+        std::thread::spawn(move || {
+            let _guard = guard;
+            let started = Instant::now();
+            let result = scan(&config, body, envelope_data);
+            report(&callback, primary, result, started.elapsed());
+        });
+    }
+}
+
+fn report(
+    callback: &ShadowCallback,
+    primary: RspamdScanReply,
+    shadow: Result<RspamdScanReply, RspamdError>,
+    elapsed: Duration,
+) {
+    if matches!(shadow, Ok(ref shadow) if shadow.is_fallback()) {
+        return;
+    }
+    let result = shadow.map(|shadow| ReplyDiff::new(&primary, &shadow));
+    callback(ShadowReport {
+        primary,
+        result,
+        elapsed,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FallbackPolicy;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    /// Serve a single scan request with `reply`
+    fn serve_once(reply: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let mut hdrs = [httparse::EMPTY_HEADER; 64];
+                let mut parsed = httparse::Request::new(&mut hdrs);
+                if let httparse::Status::Complete(offset) = parsed.parse(&request).unwrap() {
+                    let length = parsed
+                        .headers
+                        .iter()
+                        .find(|h| h.name.eq_ignore_ascii_case("Content-Length"))
+                        .map_or(0, |h| {
+                            std::str::from_utf8(h.value).unwrap().parse().unwrap()
+                        });
+                    if request.len() >= offset + length {
+                        break;
+                    }
+                }
+            }
+            let reply = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                reply.len(),
+                reply
+            );
+            stream.write_all(reply.as_bytes()).unwrap();
+        });
+        base_url
+    }
+
+    fn config(base_url: String) -> Config {
+        let mut config = Config::builder().base_url(base_url).retries(1).build();
+        config.fallback = Some(FallbackPolicy::Tempfail);
+        config
+    }
+
+    /// Wait until background shadow scans are finished
+    #[cfg(feature = "async")]
+    async fn wait_idle(shadow: &ShadowScanner) {
+        for _ in 0..200 {
+            if shadow.in_flight() == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("shadow scan is not finished");
+    }
+
+    #[cfg(feature = "sync")]
+    fn wait_idle(shadow: &ShadowScanner) {
+        for _ in 0..200 {
+            if shadow.in_flight() == 0 {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("shadow scan is not finished");
+    }
+
+    #[maybe_async::test(feature = "sync", async(feature = "async", tokio::test))]
+    async fn test_shadow_reports() {
+        const PRIMARY: &str = r#"{"action":"reject","score":15.0,"symbols":{"BAYES_SPAM":{"name":"BAYES_SPAM","score":5.0}}}"#;
+        const CANARY: &str = r#"{"action":"add header","score":7.0,"symbols":{"NEW_RULE":{"name":"NEW_RULE","score":2.0}}}"#;
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        let canary = Arc::new(config(serve_once(CANARY)));
+        let shadow = ShadowScanner::new(canary, 1.0, move |report| {
+            sink.lock().unwrap().push(report);
+        });
+
+        let reply = shadow
+            .scan(&config(serve_once(PRIMARY)), "Test", Default::default())
+            .await
+            .unwrap();
+        assert_eq!(reply.action, "reject");
+        wait_idle(&shadow).await;
+        let report = reports.lock().unwrap().pop().unwrap();
+        assert_eq!(report.primary.action, "reject");
+        let diff = report.result.unwrap();
+        assert_eq!(
+            diff.action_change,
+            Some(("reject".to_string(), "add header".to_string()))
+        );
+        assert_eq!(diff.added[0].name, "NEW_RULE");
+        assert_eq!(diff.removed[0].name, "BAYES_SPAM");
+        assert_eq!(diff.score_delta, -8.0);
+
+        // Shadow server is gone: its fallback verdict is not compared
+        let reply = shadow
+            .scan(&config(serve_once(PRIMARY)), "Test", Default::default())
+            .await
+            .unwrap();
+        assert!(!reply.is_fallback());
+        wait_idle(&shadow).await;
+        // Primary server is gone: nothing is mirrored
+        let reply = shadow
+            .scan(
+                &config("http://127.0.0.1:1".to_string()),
+                "Test",
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        assert!(reply.is_fallback());
+        wait_idle(&shadow).await;
+        assert!(reports.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sampling() {
+        let config = Arc::new(
+            Config::builder()
+                .base_url("http://localhost:11333".to_string())
+                .build(),
+        );
+        let shadow = ShadowScanner::new(config, 0.25, |_| {});
+        let sampled = (0..100).filter(|_| shadow.should_sample()).count();
+        assert_eq!(sampled, 25);
+    }
+}
@@ -1,16 +1,23 @@
+use crate::backend::cache::Lookup;
 use crate::backend::traits::*;
-use crate::config::{Config, EnvelopeData};
+use crate::backend::{
+    check_pong, decode_response, fallback_reply, parse_scan_reply, status_error, zstd_compress,
+};
+use crate::config::{Config, EnvelopeData, Priority};
 use crate::error::RspamdError;
 use crate::protocol::commands::{RspamdCommand, RspamdEndpoint};
-use crate::protocol::encryption::{httpcrypt_decrypt, httpcrypt_encrypt, make_key_header};
+use crate::protocol::encryption::{
+    httpcrypt_encrypt, httpcrypt_encrypt_with_keypair, make_key_header,
+};
 use crate::protocol::RspamdScanReply;
+use crate::telemetry;
 use attohttpc::header::{HeaderMap, HeaderName, HeaderValue};
 use attohttpc::{self, ProxySettingsBuilder, Session};
 use bytes::Bytes;
 use std::collections::HashMap;
 use std::fs;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use url::Url;
 
 pub struct SyncClient<'a> {
@@ -18,9 +25,30 @@ pub struct SyncClient<'a> {
     inner: Session,
 }
 
+/// Errors of sending a request or reading the reply
+fn transport_error(e: attohttpc::Error) -> RspamdError {
+    let timeout = matches!(
+        e.kind(),
+        attohttpc::ErrorKind::Io(io)
+            if matches!(io.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock)
+    );
+    RspamdError::TransportError {
+        message: e.to_string(),
+        timeout,
+    }
+}
+
 pub fn sync_client(options: &Config) -> Result<SyncClient<'_>, RspamdError> {
+    options.validate()?;
+
     let mut client = Session::new();
-    client.timeout(Duration::from_secs_f64(options.timeout));
+    client.timeout(options.timeout);
+    if let Some(timeout) = options.connection.connect_timeout {
+        client.connect_timeout(timeout);
+    }
+    if let Some(timeout) = options.connection.read_timeout {
+        client.read_timeout(timeout);
+    }
 
     if let Some(ref proxy) = options.proxy_config {
         let proxy = ProxySettingsBuilder::new()
@@ -59,25 +87,107 @@ impl<'a, B: AsRef<[u8]>> Request for AttoRequest<'a, B> {
     type Body = Bytes;
     type HeaderMap = HeaderMap;
 
-    fn response(mut self) -> Result<(Self::HeaderMap, Self::Body), RspamdError> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "rspamd_request",
+            skip_all,
+            fields(
+                command = self.endpoint.url,
+                upstream = %telemetry::redact_url(&self.client.config.base_url),
+                encrypted = self.client.config.encryption_key.is_some(),
+            )
+        )
+    )]
+    fn response(self) -> Result<(Self::HeaderMap, Self::Body), RspamdError> {
+        let config = self.client.config;
+        let command = self.endpoint.url;
+        let result = self.execute_guarded();
+        if let Err(ref e) = result {
+            telemetry::request_failed(command, &config.base_url, e);
+        }
+        result
+    }
+}
+
+impl<'a, B: AsRef<[u8]>> AttoRequest<'a, B> {
+    pub fn new(
+        client: SyncClient<'a>,
+        body: B,
+        command: RspamdCommand,
+        envelope_data: EnvelopeData,
+    ) -> Result<AttoRequest<'a, B>, RspamdError> {
+        Ok(Self {
+            endpoint: RspamdEndpoint::from_command(command),
+            client,
+            body,
+            envelope_data: Some(envelope_data),
+        })
+    }
+
+    /// Create a request to an endpoint, e.g. one with a query string
+    pub fn with_endpoint(
+        client: SyncClient<'a>,
+        body: B,
+        endpoint: RspamdEndpoint<'a>,
+        envelope_data: EnvelopeData,
+    ) -> AttoRequest<'a, B> {
+        Self {
+            endpoint,
+            client,
+            body,
+            envelope_data: Some(envelope_data),
+        }
+    }
+
+    /// Send the request within the limits of the rate limiter and the circuit breaker
+    fn execute_guarded(self) -> Result<(HeaderMap, Bytes), RspamdError> {
+        let config = self.client.config;
+        let priority = self
+            .envelope_data
+            .as_ref()
+            .map_or(Priority::default(), |e| e.priority);
+        let _slot = match config.rate_limiter {
+            Some(ref limiter) => Some(limiter.acquire(priority)?),
+            None => None,
+        };
+        match config.circuit_breaker {
+            Some(ref breaker) => {
+                let permit = breaker.acquire(&config.base_url)?;
+                let result = self.execute();
+                permit.record(&result);
+                result
+            }
+            None => self.execute(),
+        }
+    }
+
+    /// Send the request, retrying on transport errors, and decode the response
+    fn execute(mut self) -> Result<(HeaderMap, Bytes), RspamdError> {
         let mut retry_cnt = self.client.config.retries;
+        let mut attempt = 0;
         let mut maybe_sk = Default::default();
         let extra_hdrs: HashMap<String, String> =
             HashMap::from_iter(self.envelope_data.take().unwrap());
 
-        let response = loop {
+        let (response, started) = loop {
+            attempt += 1;
             // Check if File header is present - if so, we don't need to send the body
             let has_file_header = extra_hdrs.contains_key("File");
             let need_body = self.endpoint.need_body && !has_file_header;
+            let compression = &self.client.config.compression;
+            let compress = self.client.config.zstd
+                && need_body
+                && self.endpoint.compressible()
+                && self.body.as_ref().len() >= compression.min_size;
 
-            let mut url = Url::from_str(self.client.config.base_url.as_str())
-                .map_err(|e| RspamdError::HttpError(e.to_string()))?;
+            let (mut url, password) = self.client.config.server_url()?;
             url.set_path(self.endpoint.url);
+            url.set_query(self.endpoint.query.as_deref());
 
             let body = if need_body {
-                if self.client.config.zstd {
-                    zstd::encode_all(self.body.as_ref(), 0)
-                        .map_err(|e| RspamdError::HttpError(e.to_string()))?
+                if compress {
+                    zstd_compress(self.body.as_ref(), compression)?
                 } else {
                     self.body.as_ref().to_vec()
                 }
@@ -89,125 +199,112 @@ impl<'a, B: AsRef<[u8]>> Request for AttoRequest<'a, B> {
                 self.client.inner.post(url.clone())
             } else {
                 self.client.inner.get(url.clone())
-            }
-            .bytes(body);
+            };
 
             for (k, v) in extra_hdrs.iter() {
                 req = req.header(HeaderName::from_str(k.as_str()).unwrap(), v.clone());
             }
 
-            if let Some(ref password) = self.client.config.password {
+            if let Some(ref password) = password {
                 req = req.header("Password", password);
             }
 
-            if self.client.config.zstd && need_body {
+            if compress {
                 req = req.header("Content-Encoding", "zstd");
                 req = req.header("Compression", "zstd");
+                if let Some(ref dictionary) = compression.dictionary {
+                    req = req.header("Dictionary", dictionary.id().to_string());
+                }
             }
 
-            if let Some(ref encryption_key) = self.client.config.encryption_key {
+            let mut req = if let Some(ref encryption_key) = self.client.config.encryption_key {
                 let mut inner_req = req;
-                let body = if need_body {
-                    if self.client.config.zstd {
-                        zstd::encode_all(self.body.as_ref(), 0)?
-                    } else {
-                        self.body.as_ref().to_vec()
-                    }
+                let encrypt_started = Instant::now();
+                let encrypted = if let Some(ref keypair) = self.client.config.client_keypair {
+                    httpcrypt_encrypt_with_keypair(
+                        &url[url::Position::BeforePath..],
+                        body.as_slice(),
+                        inner_req.inspect().headers(),
+                        encryption_key.as_bytes(),
+                        keypair,
+                        &self.client.config.shared_secrets,
+                    )?
                 } else {
-                    Vec::new()
+                    httpcrypt_encrypt(
+                        &url[url::Position::BeforePath..],
+                        body.as_slice(),
+                        inner_req.inspect().headers(),
+                        encryption_key.as_bytes(),
+                    )?
                 };
-                let encrypted = httpcrypt_encrypt(
-                    url.path(),
-                    body.as_slice(),
-                    inner_req.inspect().headers(),
-                    encryption_key.as_bytes(),
-                )?;
-                req = self.client.inner.post(url).bytes(encrypted.body);
+                telemetry::encrypted(
+                    encrypted.body.len(),
+                    self.client.config.client_keypair.is_some(),
+                    encrypt_started.elapsed(),
+                );
                 let key_header =
                     make_key_header(encryption_key.as_str(), encrypted.peer_key.as_str())?;
-                req = req.header("Key", key_header);
                 maybe_sk = Some(encrypted.shared_key);
-            }
+                self.client
+                    .inner
+                    .post(url)
+                    .header("Key", key_header)
+                    .bytes(encrypted.body)
+            } else {
+                req.bytes(body)
+            };
 
-            req = req.timeout(Duration::from_secs_f64(self.client.config.timeout));
+            req = req.timeout(self.client.config.timeout);
 
+            let method = req.inspect().method().to_string();
+            telemetry::attempt_started(attempt, method.as_str(), req.inspect().body().0.len());
+            let started = Instant::now();
             match req.send() {
-                Ok(v) => break Ok(v),
+                Ok(v) => break Ok((v, started)),
                 Err(e) => {
-                    if (retry_cnt - 1) == 0 {
-                        break Err(RspamdError::HttpError(e.to_string()));
+                    let will_retry = retry_cnt > 1;
+                    telemetry::attempt_failed(
+                        self.endpoint.url,
+                        attempt,
+                        &e,
+                        started.elapsed(),
+                        will_retry,
+                    );
+                    if !will_retry {
+                        break Err(transport_error(e));
                     }
                     retry_cnt -= 1;
-                    std::thread::sleep(Duration::from_secs_f64(self.client.config.timeout));
+                    std::thread::sleep(self.client.config.timeout);
                     continue;
                 }
             }
         }?;
 
+        telemetry::response_received(
+            self.endpoint.url,
+            &self.client.config.base_url,
+            attempt,
+            response.status().as_u16(),
+            started.elapsed(),
+        );
         if !response.is_success() {
-            return Err(RspamdError::HttpError(format!(
-                "Status: {}",
-                response.status()
-            )));
+            let status = response.status().as_u16();
+            let body = response.bytes().unwrap_or_default();
+            return Err(status_error(&self.endpoint, status, &body));
         }
 
-        if let Some(sk) = maybe_sk {
-            let mut body = response
-                .bytes()
-                .map_err(|e| RspamdError::HttpError(e.to_string()))?;
-            let decrypted_offset = httpcrypt_decrypt(body.as_mut(), sk)?;
-            let mut hdrs = [httparse::EMPTY_HEADER; 64];
-            let mut parsed = httparse::Response::new(&mut hdrs);
-
-            let body_offset = parsed
-                .parse(&body.as_slice()[decrypted_offset..])
-                .map_err(|s| RspamdError::HttpError(s.to_string()))?;
-            let mut output_hdrs = HeaderMap::with_capacity(parsed.headers.len());
-            for hdr in parsed.headers.iter_mut() {
-                output_hdrs.insert(
-                    HeaderName::from_str(hdr.name)?,
-                    HeaderValue::from_str(std::str::from_utf8(hdr.value)?)?,
-                );
-            }
-            let body = if output_hdrs
-                .get("Compression")
-                .is_some_and(|hv| hv == "zstd")
-            {
-                zstd::decode_all(&body.as_slice()[body_offset.unwrap() + decrypted_offset..])?
-            } else {
-                body.as_slice()[body_offset.unwrap() + decrypted_offset..].to_vec()
-            };
-            Ok((output_hdrs, body.into()))
-        } else {
-            let headers = response.headers().clone();
-            let data = if response
-                .headers()
-                .get("Compression")
-                .is_some_and(|hv| hv == "zstd")
-            {
-                zstd::decode_all(response.bytes()?.as_slice())?
-            } else {
-                response.bytes()?
-            };
-
-            Ok((headers, data.into()))
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+            .collect();
+        let body = response.bytes().map_err(transport_error)?;
+        let (headers, body) = decode_response(headers, body, maybe_sk)?;
+        let mut output_hdrs = HeaderMap::with_capacity(headers.len());
+        for (name, value) in headers {
+            output_hdrs.insert(HeaderName::from_str(&name)?, HeaderValue::from_str(&value)?);
         }
-    }
-}
-
-impl<'a, B: AsRef<[u8]>> AttoRequest<'a, B> {
-    pub fn new(
-        client: SyncClient<'a>,
-        body: B,
-        command: RspamdCommand,
-        envelope_data: EnvelopeData,
-    ) -> Result<AttoRequest<'a, B>, RspamdError> {
-        Ok(Self {
-            endpoint: RspamdEndpoint::from_command(command),
-            client,
-            body,
-            envelope_data: Some(envelope_data),
-        })
+        Ok((output_hdrs, body.into()))
     }
 }
 
@@ -228,41 +325,75 @@ impl<'a, B: AsRef<[u8]>> AttoRequest<'a, B> {
 ///    Ok(())
 /// }
 ///
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "rspamd_scan", skip_all, fields(body_size = body.as_ref().len()))
+)]
 pub fn scan_sync<B: AsRef<[u8]>>(
     options: &Config,
     body: B,
     envelope_data: EnvelopeData,
 ) -> Result<RspamdScanReply, RspamdError> {
+    let cache_key = match options
+        .cache
+        .as_ref()
+        .map(|cache| cache.lookup(&options.base_url, body.as_ref(), &envelope_data))
+    {
+        Some(Lookup::Hit(reply)) => return Ok(*reply),
+        Some(Lookup::Miss(key)) => Some(key),
+        _ => None,
+    };
     let client = sync_client(options)?;
     let request = AttoRequest::new(client, body, RspamdCommand::Scan, envelope_data)?;
-    let (headers, body) = request
-        .response()
-        .map_err(|e| RspamdError::HttpError(e.to_string()))?;
+    let command = request.endpoint.url;
+    let (headers, body) = match request.response() {
+        Ok(reply) => reply,
+        Err(e) => return fallback_reply(options, e),
+    };
 
-    // Check for Message-Offset header to handle body_block feature
-    let response = if let Some(offset_header) = headers.get("Message-Offset") {
-        let offset = offset_header
-            .to_str()
-            .map_err(|e| RspamdError::HttpError(format!("Invalid Message-Offset header: {}", e)))?
-            .parse::<usize>()
-            .map_err(|e| RspamdError::HttpError(format!("Invalid Message-Offset value: {}", e)))?;
+    let response = parse_scan_reply(
+        options,
+        command,
+        headers.get("Message-Offset").map(|v| v.as_bytes()),
+        &body,
+    )?;
+    if let (Some(cache), Some(key)) = (options.cache.as_ref(), cache_key) {
+        cache.insert(key, &response);
+    }
 
-        if offset < body.len() {
-            // Split body into JSON part and rewritten body part
-            let json_part = &body[..offset];
-            let body_part = &body[offset..];
+    Ok(response)
+}
 
-            let mut response = serde_json::from_slice::<RspamdScanReply>(json_part)?;
-            response.rewritten_body = Some(body_part.to_vec());
-            response
-        } else {
-            // Offset is out of bounds, parse entire body as JSON
-            serde_json::from_slice::<RspamdScanReply>(body.as_ref())?
-        }
-    } else {
-        // No Message-Offset header, parse entire body as JSON
-        serde_json::from_slice::<RspamdScanReply>(body.as_ref())?
-    };
+/// Send a request to an endpoint, returning the decoded reply body
+pub(crate) fn request_sync<B: AsRef<[u8]>>(
+    options: &Config,
+    endpoint: RspamdEndpoint<'_>,
+    body: B,
+    envelope_data: EnvelopeData,
+) -> Result<Bytes, RspamdError> {
+    let client = sync_client(options)?;
+    let request = AttoRequest::with_endpoint(client, body, endpoint, envelope_data);
+    let (_, body) = request.response()?;
+    Ok(body)
+}
 
-    Ok(response)
+/// Check that the server is alive with `/ping`, returning the round-trip time.
+/// Example:
+/// ```rust,no_run
+/// use rspamd_client::config::Config;
+/// use rspamd_client::ping_sync;
+///
+/// let config = Config::builder()
+///     .base_url("http://localhost:11333".to_string())
+///     .build();
+/// let rtt = ping_sync(&config)?;
+/// # Ok::<(), rspamd_client::error::RspamdError>(())
+/// ```
+pub fn ping_sync(options: &Config) -> Result<Duration, RspamdError> {
+    let started = Instant::now();
+    let client = sync_client(options)?;
+    let request = AttoRequest::new(client, &[][..], RspamdCommand::Ping, Default::default())?;
+    let (_, body) = request.response()?;
+    check_pong(&body)?;
+    Ok(started.elapsed())
 }
@@ -3,13 +3,27 @@
 //!
 //! The `Config` struct allows you to customize various aspects of the client, including the base URL, proxy settings, and TLS settings.
 //!
+//! Besides the builder, `Config` can be deserialized with serde (e.g. from TOML or JSON files) or loaded
+//! from environment variables with `Config::from_env`. Durations are accepted either as a number of
+//! seconds or as a string with a unit suffix (`500ms`, `30s`, `1m`).
+//!
 
+use crate::backend::cache::ScanCache;
+use crate::backend::circuit_breaker::CircuitBreaker;
+use crate::backend::rate_limit::RateLimiter;
+use crate::error::RspamdError;
+use crate::protocol::encryption::SharedSecretCache;
+use crate::protocol::{RspamdKeypair, RspamdPublicKey};
+use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
 use std::iter::IntoIterator;
+use std::sync::Arc;
+use std::time::Duration;
 use typed_builder::TypedBuilder;
+use url::Url;
 
 /// Custom TLS settings for the Rspamd client
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct TlsSettings {
     /// Path to the TLS certificate file
     pub cert_path: String,
@@ -18,23 +32,182 @@ pub struct TlsSettings {
     pub key_path: String,
 
     /// Optional path to the TLS CA file
+    #[serde(default)]
     pub ca_path: Option<String>,
 }
 
 /// Proxy configuration for the Rspamd client
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct ProxyConfig {
     /// Proxy server URL
     pub proxy_url: String,
 
     /// Optional username for proxy authentication
+    #[serde(default)]
     pub username: Option<String>,
 
     /// Optional password for proxy authentication
+    #[serde(default)]
     pub password: Option<String>,
 }
 
-#[derive(TypedBuilder, Debug, PartialEq, Default)]
+/// Connection settings of the HTTP client.
+///
+/// The sync client (`attohttpc`) supports only connect and read timeouts, it opens a new
+/// connection for each request; other settings are rejected by `Config::validate` there.
+#[derive(TypedBuilder, Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct ConnectionSettings {
+    /// Timeout for establishing a connection, including TLS handshake
+    #[builder(default, setter(strip_option))]
+    #[serde(deserialize_with = "deserialize_opt_duration")]
+    pub connect_timeout: Option<Duration>,
+
+    /// Idle timeout: maximum time to wait for data from the server
+    #[builder(default, setter(strip_option))]
+    #[serde(deserialize_with = "deserialize_opt_duration")]
+    pub read_timeout: Option<Duration>,
+
+    /// How long idle connections are kept in the pool (async only)
+    #[builder(default, setter(strip_option))]
+    #[serde(deserialize_with = "deserialize_opt_duration")]
+    pub pool_idle_timeout: Option<Duration>,
+
+    /// Maximum number of idle connections per host (async only)
+    #[builder(default, setter(strip_option))]
+    pub pool_max_idle_per_host: Option<usize>,
+
+    /// Interval of TCP keep-alive probes (async only)
+    #[builder(default, setter(strip_option))]
+    #[serde(deserialize_with = "deserialize_opt_duration")]
+    pub tcp_keepalive: Option<Duration>,
+
+    /// Set `TCP_NODELAY` on connections (async only)
+    #[builder(default, setter(strip_option))]
+    pub tcp_nodelay: Option<bool>,
+
+    /// Use HTTP/2 without negotiation, for `https` endpoints behind an HTTP/2 capable proxy
+    /// (async only)
+    #[builder(default)]
+    pub http2_prior_knowledge: bool,
+}
+
+impl ConnectionSettings {
+    /// Check that the settings are supported by the enabled backend
+    fn validate(&self, url: &Url) -> Result<(), RspamdError> {
+        if self.http2_prior_knowledge && url.scheme() != "https" {
+            return Err(RspamdError::ConfigError(
+                "HTTP/2 prior knowledge requires an https base URL".to_string(),
+            ));
+        }
+        #[cfg(feature = "sync")]
+        {
+            let unsupported = [
+                ("pool_idle_timeout", self.pool_idle_timeout.is_some()),
+                (
+                    "pool_max_idle_per_host",
+                    self.pool_max_idle_per_host.is_some(),
+                ),
+                ("tcp_keepalive", self.tcp_keepalive.is_some()),
+                ("tcp_nodelay", self.tcp_nodelay.is_some()),
+                ("http2_prior_knowledge", self.http2_prior_knowledge),
+            ];
+            if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+                return Err(RspamdError::ConfigError(format!(
+                    "{} is not supported by the sync client",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Zstd dictionary shared with the server (`zstd_input_dictionary` in the Rspamd `compression` options)
+#[derive(Clone, PartialEq)]
+pub struct ZstdDictionary {
+    id: u32,
+    data: Arc<Vec<u8>>,
+}
+
+impl ZstdDictionary {
+    /// Create a dictionary from its content, it must have an id (raw content dictionaries
+    /// are not supported, as the server identifies dictionaries by id)
+    pub fn new(data: Vec<u8>) -> Result<Self, RspamdError> {
+        let id = zstd::zstd_safe::get_dict_id_from_dict(&data)
+            .ok_or_else(|| RspamdError::ConfigError("Zstd dictionary has no id".to_string()))?;
+        Ok(Self {
+            id: id.get(),
+            data: Arc::new(data),
+        })
+    }
+
+    /// Load a dictionary from a file, e.g. one trained with `zstd --train`
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self, RspamdError> {
+        let path = path.as_ref();
+        let data = std::fs::read(path).map_err(|e| {
+            RspamdError::ConfigError(format!(
+                "Cannot read zstd dictionary {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        Self::new(data)
+    }
+
+    /// Dictionary id, sent to the server in the `Dictionary` header
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Dictionary content
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl std::fmt::Debug for ZstdDictionary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ZstdDictionary")
+            .field("id", &self.id)
+            .field("size", &self.data.len())
+            .finish()
+    }
+}
+
+/// Dictionaries are configured by the path of the file
+impl<'de> Deserialize<'de> for ZstdDictionary {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let path = String::deserialize(deserializer)?;
+        Self::from_file(path).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Compression settings of request bodies, used when `Config::zstd` is enabled
+#[derive(TypedBuilder, Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CompressionSettings {
+    /// Zstd compression level, 0 means the zstd default level
+    #[builder(default)]
+    pub level: i32,
+
+    /// Messages smaller than this size (in bytes) are sent uncompressed
+    #[builder(default)]
+    pub min_size: usize,
+
+    /// Optional dictionary, it must be the same as configured on the server
+    #[builder(default, setter(strip_option))]
+    pub dictionary: Option<ZstdDictionary>,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+#[derive(TypedBuilder, Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default)]
 pub struct EnvelopeData {
     /// Sender email address
     #[builder(default, setter(strip_option))]
@@ -76,6 +249,15 @@ pub struct EnvelopeData {
     /// Optional additional headers
     #[builder(default)]
     pub additional_headers: HashMap<String, String>,
+
+    /// Priority of the request for client-side rate limiting, it is not sent to the server
+    #[builder(default)]
+    pub priority: Priority,
+
+    /// Scan without looking up the client-side scan cache, the reply is still stored in it;
+    /// it is not sent to the server
+    #[builder(default)]
+    pub bypass_cache: bool,
 }
 
 impl IntoIterator for EnvelopeData {
@@ -116,37 +298,757 @@ impl IntoIterator for EnvelopeData {
     }
 }
 
+/// Priority class of a request, see `backend::rate_limit::RateLimiter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Live traffic, e.g. scans of incoming mail
+    #[default]
+    Live,
+    /// Batch traffic, e.g. learning jobs and bulk rescans: it waits while live requests wait
+    Batch,
+}
+
+/// Options for learning messages (`/learnspam` and `/learnham`)
+#[derive(TypedBuilder, Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct LearnOptions {
+    /// Recipient mailbox for per-user statistics (`Deliver-To` header)
+    #[builder(default, setter(strip_option))]
+    pub deliver_to: Option<String>,
+
+    /// Authenticated user for per-user statistics (`User` header)
+    #[builder(default, setter(strip_option))]
+    pub user: Option<String>,
+
+    /// Name of the classifier to learn (`Classifier` header), all classifiers are learned if not set
+    #[builder(default, setter(strip_option))]
+    pub classifier: Option<String>,
+
+    /// Other envelope data of the learned message
+    #[builder(default)]
+    pub envelope: EnvelopeData,
+}
+
+impl From<LearnOptions> for EnvelopeData {
+    fn from(options: LearnOptions) -> Self {
+        let mut envelope = options.envelope;
+        if let Some(user) = options.user {
+            envelope.user = Some(user);
+        }
+        if let Some(deliver_to) = options.deliver_to {
+            envelope
+                .additional_headers
+                .insert("Deliver-To".to_string(), deliver_to);
+        }
+        if let Some(classifier) = options.classifier {
+            envelope
+                .additional_headers
+                .insert("Classifier".to_string(), classifier);
+        }
+        envelope
+    }
+}
+
+/// Thresholds of the circuit breaker, see `backend::circuit_breaker::CircuitBreaker`
+#[derive(TypedBuilder, Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakerSettings {
+    /// Number of last requests to an upstream used to compute failure and slow request rates
+    #[builder(default = 20)]
+    pub window_size: usize,
+
+    /// Minimum number of requests in the window before the circuit can open
+    #[builder(default = 10)]
+    pub minimum_requests: usize,
+
+    /// Rate of failed requests (from 0.0 to 1.0) that opens the circuit
+    #[builder(default = 0.5)]
+    pub failure_rate_threshold: f64,
+
+    /// Requests slower than this are counted as slow, slow requests are not tracked if not set
+    #[builder(default, setter(strip_option))]
+    #[serde(deserialize_with = "deserialize_opt_duration")]
+    pub slow_request_duration: Option<Duration>,
+
+    /// Rate of slow requests (from 0.0 to 1.0) that opens the circuit
+    #[builder(default = 1.0)]
+    pub slow_request_rate_threshold: f64,
+
+    /// How long the circuit stays open before probe requests are allowed
+    #[builder(default = Duration::from_secs(30))]
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub open_duration: Duration,
+
+    /// Number of successful probe requests needed to close a half-open circuit
+    #[builder(default = 3)]
+    pub half_open_requests: u32,
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl CircuitBreakerSettings {
+    /// Check that thresholds are in range
+    pub fn validate(&self) -> Result<(), RspamdError> {
+        let rate = 0.0..=1.0;
+        if !rate.contains(&self.failure_rate_threshold)
+            || !rate.contains(&self.slow_request_rate_threshold)
+        {
+            return Err(RspamdError::ConfigError(
+                "Circuit breaker rate thresholds must be between 0.0 and 1.0".to_string(),
+            ));
+        }
+        if self.window_size == 0 || self.minimum_requests > self.window_size {
+            return Err(RspamdError::ConfigError(format!(
+                "Invalid circuit breaker window: {} requests, minimum {}",
+                self.window_size, self.minimum_requests
+            )));
+        }
+        if self.half_open_requests == 0 {
+            return Err(RspamdError::ConfigError(
+                "Circuit breaker needs at least one half-open request".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Limits of the rate limiter, see `backend::rate_limit::RateLimiter`
+#[derive(TypedBuilder, Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// Sustained rate of requests per second, the rate is not limited if not set
+    #[builder(default, setter(strip_option))]
+    pub requests_per_second: Option<f64>,
+
+    /// Number of requests that can be sent at once above the rate, at least 1
+    #[builder(default = 1)]
+    pub burst: u32,
+
+    /// Maximum number of requests in flight, not limited if not set
+    #[builder(default, setter(strip_option))]
+    pub max_in_flight: Option<usize>,
+
+    /// Maximum number of batch requests in flight, so that some capacity is left to live traffic
+    #[builder(default, setter(strip_option))]
+    pub batch_max_in_flight: Option<usize>,
+
+    /// Maximum time to wait before a request is sent, `RspamdError::RateLimited` is returned
+    /// when it is exceeded; requests wait as long as needed if not set
+    #[builder(default, setter(strip_option))]
+    #[serde(deserialize_with = "deserialize_opt_duration")]
+    pub max_wait: Option<Duration>,
+}
+
+impl RateLimitSettings {
+    /// Check that limits are positive
+    pub fn validate(&self) -> Result<(), RspamdError> {
+        if self
+            .requests_per_second
+            .is_some_and(|rps| !(rps.is_finite() && rps > 0.0))
+            || self.burst == 0
+            || self.max_in_flight == Some(0)
+            || self.batch_max_in_flight == Some(0)
+        {
+            return Err(RspamdError::ConfigError(format!(
+                "Invalid rate limits: {:?}",
+                self
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Limits of the scan cache, see `backend::cache::ScanCache`
+#[derive(TypedBuilder, Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CacheSettings {
+    /// Maximum number of cached replies, least recently used ones are evicted first
+    #[builder(default = 1024)]
+    pub capacity: usize,
+
+    /// How long a reply is kept
+    #[builder(default = Duration::from_secs(60))]
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub ttl: Duration,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl CacheSettings {
+    /// Check that limits are not zero
+    pub fn validate(&self) -> Result<(), RspamdError> {
+        if self.capacity == 0 || self.ttl.is_zero() {
+            return Err(RspamdError::ConfigError(format!(
+                "Invalid scan cache limits: {:?}",
+                self
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Verdict returned instead of an error when the server cannot be reached or times out,
+/// see `RspamdScanReply::fallback`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FallbackPolicy {
+    /// Fail open: accept the message (`no action`)
+    Accept,
+    /// Ask the sender to retry later (`soft reject`)
+    Tempfail,
+    /// Fail closed: reject the message (`reject`)
+    Reject,
+}
+
+impl FallbackPolicy {
+    /// Rspamd action of the fallback verdict
+    pub fn action(&self) -> &'static str {
+        match self {
+            FallbackPolicy::Accept => "no action",
+            FallbackPolicy::Tempfail => "soft reject",
+            FallbackPolicy::Reject => "reject",
+        }
+    }
+}
+
+impl std::str::FromStr for FallbackPolicy {
+    type Err = RspamdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "accept" => Ok(FallbackPolicy::Accept),
+            "tempfail" => Ok(FallbackPolicy::Tempfail),
+            "reject" => Ok(FallbackPolicy::Reject),
+            other => Err(RspamdError::ConfigError(format!(
+                "Invalid fallback policy: {}",
+                other
+            ))),
+        }
+    }
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_retries() -> u32 {
+    1
+}
+
+fn default_zstd() -> bool {
+    true
+}
+
+/// Parses a duration given either as a number of seconds or as a string with a unit suffix
+/// (`ms`, `s`, `m` or `h`), e.g. `1.5`, `"500ms"` or `"30s"`
+pub fn parse_duration(value: &str) -> Result<Duration, RspamdError> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| RspamdError::ConfigError(format!("Invalid duration: {}", value)))?;
+    let seconds = match unit.trim() {
+        "" | "s" => number,
+        "ms" => number / 1000.0,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => {
+            return Err(RspamdError::ConfigError(format!(
+                "Invalid duration unit: {}",
+                value
+            )))
+        }
+    };
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| RspamdError::ConfigError(format!("Invalid duration: {}", value)))
+}
+
+/// Deserializes a duration using `parse_duration` rules
+pub(crate) fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawDuration {
+        Seconds(f64),
+        Text(String),
+    }
+
+    match RawDuration::deserialize(deserializer)? {
+        RawDuration::Seconds(secs) => {
+            Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom)
+        }
+        RawDuration::Text(text) => parse_duration(&text).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Deserializes an optional duration using `parse_duration` rules
+fn deserialize_opt_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "deserialize_duration")] Duration);
+
+    Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(d)| d))
+}
+
 /// Configuration for Rspamd client
-#[derive(TypedBuilder, Debug, PartialEq)]
+#[derive(TypedBuilder, Debug, Deserialize)]
 pub struct Config {
     /// Base URL of Rspamd server
+    /// It can contain a password, e.g. `http://:password@localhost:11334`, used if `password` is not set
     pub base_url: String,
 
     /// Optional API key for authentication
     #[builder(default, setter(strip_option))]
+    #[serde(default)]
     pub password: Option<String>,
 
-    /// Timeout duration for requests
-    #[builder(default = 30.0)]
-    pub timeout: f64,
+    /// Timeout of a whole request, from connecting until the reply body is read
+    #[builder(default = default_timeout())]
+    #[serde(default = "default_timeout", deserialize_with = "deserialize_duration")]
+    pub timeout: Duration,
+
+    /// Connection settings: connect and idle timeouts, pooling, TCP options and HTTP/2
+    #[builder(default)]
+    #[serde(default)]
+    pub connection: ConnectionSettings,
 
     /// Number of retries for requests
-    #[builder(default = 1)]
+    #[builder(default = default_retries())]
+    #[serde(default = "default_retries")]
     pub retries: u32,
 
     /// Custom TLS settings for the asynchronous client
     #[builder(default, setter(strip_option))]
+    #[serde(default)]
     pub tls_settings: Option<TlsSettings>,
 
     /// Proxy configuration for the asynchronous client
     #[builder(default, setter(strip_option))]
+    #[serde(default)]
     pub proxy_config: Option<ProxyConfig>,
 
     /// Use zstd compression
-    #[builder(default = true)]
+    #[builder(default = default_zstd())]
+    #[serde(default = "default_zstd")]
     pub zstd: bool,
 
+    /// Compression level, minimum size and dictionary
+    #[builder(default)]
+    #[serde(default)]
+    pub compression: CompressionSettings,
+
     /// Encryption key if using native HTTPCrypt encryption (must be in Rspamd base32 format)
+    /// It is validated when a client is built, see `Config::validate`
     #[builder(default, setter(strip_option))]
+    #[serde(default)]
     pub encryption_key: Option<String>,
+
+    /// Static client keypair for HTTPCrypt; if not set, an ephemeral keypair is generated for each request
+    /// A static keypair allows to reuse shared secrets with the server, saving CPU on each request
+    #[builder(default, setter(strip_option))]
+    #[serde(default)]
+    pub client_keypair: Option<RspamdKeypair>,
+
+    /// Verdict returned by scans when the server cannot be reached or times out;
+    /// if not set, such failures are returned as errors
+    #[builder(default, setter(strip_option))]
+    #[serde(default)]
+    pub fallback: Option<FallbackPolicy>,
+
+    /// Circuit breaker shared by all requests made with this configuration; clones of the breaker
+    /// share its state, so a clone can be kept to monitor upstreams
+    #[builder(default, setter(strip_option))]
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreaker>,
+
+    /// Rate limiter shared by all requests made with this configuration, see `EnvelopeData::priority`
+    #[builder(default, setter(strip_option))]
+    #[serde(default)]
+    pub rate_limiter: Option<RateLimiter>,
+
+    /// Cache of scan replies shared by all requests made with this configuration,
+    /// see `EnvelopeData::bypass_cache`
+    #[builder(default, setter(strip_option))]
+    #[serde(default)]
+    pub cache: Option<ScanCache>,
+
+    /// Shared secrets derived from `client_keypair`
+    #[builder(default, setter(skip))]
+    #[serde(skip)]
+    pub(crate) shared_secrets: SharedSecretCache,
+}
+
+/// Cached shared secrets are derived state, they are not compared
+impl PartialEq for Config {
+    fn eq(&self, other: &Self) -> bool {
+        let Config {
+            base_url,
+            password,
+            timeout,
+            connection,
+            retries,
+            tls_settings,
+            proxy_config,
+            zstd,
+            compression,
+            encryption_key,
+            client_keypair,
+            fallback,
+            circuit_breaker,
+            rate_limiter,
+            cache,
+            shared_secrets: _,
+        } = self;
+        *base_url == other.base_url
+            && *password == other.password
+            && *timeout == other.timeout
+            && *connection == other.connection
+            && *retries == other.retries
+            && *tls_settings == other.tls_settings
+            && *proxy_config == other.proxy_config
+            && *zstd == other.zstd
+            && *compression == other.compression
+            && *encryption_key == other.encryption_key
+            && *client_keypair == other.client_keypair
+            && *fallback == other.fallback
+            && *circuit_breaker == other.circuit_breaker
+            && *rate_limiter == other.rate_limiter
+            && *cache == other.cache
+    }
+}
+
+impl Config {
+    /// Parsed server public key used for HTTPCrypt, if encryption is enabled
+    pub fn encryption_public_key(&self) -> Result<Option<RspamdPublicKey>, RspamdError> {
+        self.encryption_key
+            .as_deref()
+            .map(|key| {
+                key.parse::<RspamdPublicKey>()
+                    .map_err(|e| RspamdError::ConfigError(format!("Invalid encryption key: {}", e)))
+            })
+            .transpose()
+    }
+
+    /// Copy of this configuration for another server, e.g. a neighbour in a cluster.
+    /// A password embedded in `base_url` is kept as `password` of the copy.
+    /// Cached shared secrets are not copied.
+    pub fn with_base_url(&self, base_url: String) -> Config {
+        let password = self
+            .server_url()
+            .map_or_else(|_| self.password.clone(), |(_, password)| password);
+        Config {
+            base_url,
+            password,
+            timeout: self.timeout,
+            connection: self.connection.clone(),
+            retries: self.retries,
+            tls_settings: self.tls_settings.clone(),
+            proxy_config: self.proxy_config.clone(),
+            zstd: self.zstd,
+            compression: self.compression.clone(),
+            encryption_key: self.encryption_key.clone(),
+            client_keypair: self.client_keypair.clone(),
+            fallback: self.fallback,
+            circuit_breaker: self.circuit_breaker.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            cache: self.cache.clone(),
+            shared_secrets: Default::default(),
+        }
+    }
+
+    /// Check that the configuration is consistent, this is done when a client is built
+    pub fn validate(&self) -> Result<(), RspamdError> {
+        let (url, _) = self.server_url()?;
+        self.connection.validate(&url)?;
+        self.encryption_public_key()?;
+        if self.retries == 0 {
+            return Err(RspamdError::ConfigError(
+                "Number of retries must be at least 1".to_string(),
+            ));
+        }
+        if let Some(ref breaker) = self.circuit_breaker {
+            breaker.settings().validate()?;
+        }
+        if let Some(ref limiter) = self.rate_limiter {
+            limiter.settings().validate()?;
+        }
+        if let Some(ref cache) = self.cache {
+            cache.settings().validate()?;
+        }
+        if !zstd::compression_level_range().contains(&self.compression.level) {
+            return Err(RspamdError::ConfigError(format!(
+                "Invalid zstd compression level: {}",
+                self.compression.level
+            )));
+        }
+        Ok(())
+    }
+
+    /// Server URL without credentials and the password to use with it
+    /// Explicit `password` takes precedence over the one embedded in `base_url`
+    pub fn server_url(&self) -> Result<(Url, Option<String>), RspamdError> {
+        let mut url = Url::parse(self.base_url.as_str())?;
+        let embedded = url
+            .password()
+            .map(|p| {
+                percent_encoding::percent_decode_str(p)
+                    .decode_utf8()
+                    .map(|p| p.into_owned())
+            })
+            .transpose()
+            .map_err(|e| RspamdError::ConfigError(format!("Invalid password in URL: {}", e)))?;
+        if embedded.is_some() || !url.username().is_empty() {
+            url.set_username("")
+                .and_then(|_| url.set_password(None))
+                .map_err(|_| RspamdError::ConfigError("Invalid base URL".to_string()))?;
+        }
+        Ok((url, self.password.clone().or(embedded)))
+    }
+
+    /// Load configuration from environment variables:
+    ///
+    /// - `RSPAMD_URL`: base URL, required, may contain a password
+    /// - `RSPAMD_PASSWORD`: controller password
+    /// - `RSPAMD_KEY`: server public key for HTTPCrypt encryption
+    /// - `RSPAMD_TIMEOUT`: timeout, e.g. `30` or `500ms`
+    /// - `RSPAMD_CONNECT_TIMEOUT`: connect timeout
+    /// - `RSPAMD_READ_TIMEOUT`: idle timeout while waiting for data
+    /// - `RSPAMD_RETRIES`: number of retries
+    /// - `RSPAMD_ZSTD`: whether to use zstd compression (`true` or `false`)
+    /// - `RSPAMD_FALLBACK`: fallback policy (`accept`, `tempfail` or `reject`)
+    pub fn from_env() -> Result<Config, RspamdError> {
+        Self::from_env_with(|name| std::env::var(name).ok())
+    }
+
+    fn from_env_with<F: Fn(&str) -> Option<String>>(var: F) -> Result<Config, RspamdError> {
+        let base_url = var("RSPAMD_URL")
+            .ok_or_else(|| RspamdError::ConfigError("RSPAMD_URL is not set".to_string()))?;
+        let mut config = Config::builder().base_url(base_url).build();
+        config.password = var("RSPAMD_PASSWORD");
+        config.encryption_key = var("RSPAMD_KEY");
+        if let Some(timeout) = var("RSPAMD_TIMEOUT") {
+            config.timeout = parse_duration(&timeout)?;
+        }
+        if let Some(timeout) = var("RSPAMD_CONNECT_TIMEOUT") {
+            config.connection.connect_timeout = Some(parse_duration(&timeout)?);
+        }
+        if let Some(timeout) = var("RSPAMD_READ_TIMEOUT") {
+            config.connection.read_timeout = Some(parse_duration(&timeout)?);
+        }
+        if let Some(retries) = var("RSPAMD_RETRIES") {
+            config.retries = retries
+                .parse()
+                .map_err(|e| RspamdError::ConfigError(format!("Invalid RSPAMD_RETRIES: {}", e)))?;
+        }
+        if let Some(zstd) = var("RSPAMD_ZSTD") {
+            config.zstd = zstd
+                .parse()
+                .map_err(|e| RspamdError::ConfigError(format!("Invalid RSPAMD_ZSTD: {}", e)))?;
+        }
+        if let Some(fallback) = var("RSPAMD_FALLBACK") {
+            config.fallback = Some(fallback.parse()?);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_encryption_key() {
+        let config = Config::builder()
+            .base_url("http://localhost:11333".to_string())
+            .encryption_key("k4nz984k36xmcynm1hr9kdbn6jhcxf4ggbrb1quay7f88rpm9kay".to_string())
+            .build();
+        assert!(config.validate().is_ok());
+
+        let config = Config::builder()
+            .base_url("http://localhost:11333".to_string())
+            .encryption_key("not a key".to_string())
+            .build();
+        assert!(matches!(
+            config.validate(),
+            Err(RspamdError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_retries() {
+        let config = Config::builder()
+            .base_url("http://localhost:11333".to_string())
+            .retries(0)
+            .build();
+        assert!(matches!(
+            config.validate(),
+            Err(RspamdError::ConfigError(_))
+        ));
+
+        let config: Config =
+            serde_json::from_str(r#"{"base_url": "http://localhost:11333", "retries": 0}"#)
+                .unwrap();
+        assert!(config.validate().is_err());
+
+        let vars = HashMap::from([
+            ("RSPAMD_URL", "http://localhost:11333"),
+            ("RSPAMD_RETRIES", "0"),
+        ]);
+        assert!(Config::from_env_with(|name| vars.get(name).map(|v| v.to_string())).is_err());
+    }
+
+    #[test]
+    fn test_with_base_url() {
+        let config = Config::builder()
+            .base_url("http://:secret@localhost:11334".to_string())
+            .build();
+        let neighbour = config.with_base_url("http://neighbour:11334/".to_string());
+        assert_eq!(neighbour.password.as_deref(), Some("secret"));
+        let (url, password) = neighbour.server_url().unwrap();
+        assert_eq!(url.as_str(), "http://neighbour:11334/");
+        assert_eq!(password.as_deref(), Some("secret"));
+
+        // Explicit password takes precedence over the embedded one
+        let mut config = config;
+        config.password = Some("explicit".to_string());
+        let neighbour = config.with_base_url("http://neighbour:11334/".to_string());
+        assert_eq!(neighbour.password.as_deref(), Some("explicit"));
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "base_url": "http://localhost:11333",
+                "timeout": "500ms",
+                "proxy_config": { "proxy_url": "http://proxy:8080" },
+                "fallback": "accept",
+                "cache": { "ttl": "5m" }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.timeout, Duration::from_millis(500));
+        assert_eq!(config.retries, 1);
+        assert!(config.zstd);
+        assert_eq!(config.proxy_config.unwrap().proxy_url, "http://proxy:8080");
+        assert_eq!(config.fallback.map(|f| f.action()), Some("no action"));
+        let cache = config.cache.unwrap();
+        assert_eq!(cache.settings().ttl, Duration::from_secs(300));
+        assert_eq!(cache.settings().capacity, 1024);
+
+        let config: Config =
+            serde_json::from_str(r#"{"base_url": "http://localhost:11333", "timeout": 2.5}"#)
+                .unwrap();
+        assert_eq!(config.timeout, Duration::from_millis(2500));
+
+        let envelope: EnvelopeData =
+            serde_json::from_str(r#"{"from": "user@example.com", "rcpt": ["a@example.com"]}"#)
+                .unwrap();
+        assert_eq!(envelope.from.as_deref(), Some("user@example.com"));
+        assert_eq!(envelope.rcpt, vec!["a@example.com".to_string()]);
+    }
+
+    #[test]
+    fn test_from_env() {
+        let vars = HashMap::from([
+            ("RSPAMD_URL", "http://:s%40cret@localhost:11334"),
+            ("RSPAMD_TIMEOUT", "10s"),
+            ("RSPAMD_RETRIES", "3"),
+            ("RSPAMD_FALLBACK", "Tempfail"),
+        ]);
+        let config = Config::from_env_with(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        assert_eq!(config.timeout, Duration::from_secs(10));
+        assert_eq!(config.retries, 3);
+        assert_eq!(config.fallback, Some(FallbackPolicy::Tempfail));
+        assert!("fail".parse::<FallbackPolicy>().is_err());
+        let (url, password) = config.server_url().unwrap();
+        assert_eq!(url.as_str(), "http://localhost:11334/");
+        assert_eq!(password.as_deref(), Some("s@cret"));
+
+        assert!(Config::from_env_with(|_| None).is_err());
+    }
+
+    #[test]
+    fn test_learn_options_headers() {
+        let options = LearnOptions::builder()
+            .deliver_to("user@example.com".to_string())
+            .user("user".to_string())
+            .classifier("bayes_user".to_string())
+            .build();
+        let headers: HashMap<String, String> = EnvelopeData::from(options).into_iter().collect();
+        assert_eq!(headers["Deliver-To"], "user@example.com");
+        assert_eq!(headers["User"], "user");
+        assert_eq!(headers["Classifier"], "bayes_user");
+    }
+
+    #[test]
+    fn test_connection_settings() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "base_url": "https://localhost:11333",
+                "connection": {"connect_timeout": "2s", "read_timeout": 5}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.connection.connect_timeout,
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(config.connection.read_timeout, Some(Duration::from_secs(5)));
+        assert!(config.validate().is_ok());
+
+        let config = Config::builder()
+            .base_url("http://localhost:11333".to_string())
+            .connection(
+                ConnectionSettings::builder()
+                    .http2_prior_knowledge(true)
+                    .build(),
+            )
+            .build();
+        assert!(matches!(
+            config.validate(),
+            Err(RspamdError::ConfigError(_))
+        ));
+
+        let config = Config::builder()
+            .base_url("http://localhost:11333".to_string())
+            .connection(ConnectionSettings::builder().tcp_nodelay(true).build())
+            .build();
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "async"));
+    }
+
+    #[test]
+    fn test_compression_settings() {
+        let config: Config = serde_json::from_str(
+            r#"{"base_url": "http://localhost:11333", "compression": {"level": 19, "min_size": 1024}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.compression.level, 19);
+        assert_eq!(config.compression.min_size, 1024);
+        assert!(config.validate().is_ok());
+
+        let config = Config::builder()
+            .base_url("http://localhost:11333".to_string())
+            .compression(CompressionSettings::builder().level(100).build())
+            .build();
+        assert!(config.validate().is_err());
+
+        assert!(ZstdDictionary::new(b"raw content without id".to_vec()).is_err());
+        assert!(ZstdDictionary::from_file("/nonexistent/dictionary").is_err());
+    }
 }
@@ -25,6 +25,41 @@ pub enum RspamdError {
     #[error("Encryption error: {0}")]
     EncryptionError(String),
 
+    #[error("Authorization error: {0}")]
+    AuthorizationError(String),
+
+    #[error("Transport error: {message}")]
+    TransportError { message: String, timeout: bool },
+
+    #[error("Circuit breaker is open for {0}")]
+    CircuitOpen(String),
+
+    #[error("Rate limited: {0}")]
+    RateLimited(String),
+
+    #[error("Discovery error: {0}")]
+    DiscoveryError(String),
+
+    #[error("Metrics parsing error: {0}")]
+    MetricsParseError(String),
+
+    #[error("Server error ({status}): {message}")]
+    ServerError { status: u16, message: String },
+
+    #[error("Invalid action thresholds: {lower} ({lower_score}) must be below {higher} ({higher_score})")]
+    InvalidThresholds {
+        lower: &'static str,
+        lower_score: f64,
+        higher: &'static str,
+        higher_score: f64,
+    },
+
+    #[error("Invalid score for {0}")]
+    InvalidScore(String),
+
+    #[error("Invalid selector: {0}")]
+    InvalidSelector(String),
+
     #[error("UTF8 process error: {0}")]
     UTF8Error(#[from] std::str::Utf8Error),
 
@@ -52,3 +87,40 @@ pub enum RspamdError {
     #[error("HTTP error: {0}")]
     HTTPError(#[from] attohttpc::Error),
 }
+
+impl RspamdError {
+    /// Short error kind, suitable for metric labels and logs
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RspamdError::HttpError(_) | RspamdError::HTTPError(_) => "http",
+            RspamdError::SerdeError(_) => "serde",
+            RspamdError::ConfigError(_) => "config",
+            RspamdError::Unknown => "unknown",
+            RspamdError::IOError(_) => "io",
+            RspamdError::ParseError(_) => "url",
+            RspamdError::EncryptionError(_) => "encryption",
+            RspamdError::AuthorizationError(_) => "auth",
+            RspamdError::TransportError { timeout: true, .. } => "timeout",
+            RspamdError::TransportError { .. } => "transport",
+            RspamdError::CircuitOpen(_) => "circuit_open",
+            RspamdError::RateLimited(_) => "rate_limited",
+            RspamdError::DiscoveryError(_) => "discovery",
+            RspamdError::MetricsParseError(_) => "metrics",
+            RspamdError::ServerError { .. } => "server",
+            RspamdError::InvalidThresholds { .. }
+            | RspamdError::InvalidScore(_)
+            | RspamdError::InvalidSelector(_) => "validation",
+            RspamdError::UTF8Error(_) => "utf8",
+            RspamdError::InvalidHeaderValue(_) | RspamdError::InvalidHeaderName(_) => "header",
+        }
+    }
+
+    /// Whether the server could not be reached, has not replied in time, or is considered
+    /// unavailable by the circuit breaker
+    pub fn is_transport(&self) -> bool {
+        matches!(
+            self,
+            RspamdError::TransportError { .. } | RspamdError::CircuitOpen(_)
+        )
+    }
+}
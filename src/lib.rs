@@ -10,6 +10,8 @@
 //! - **Async**: Asynchronous client using `reqwest`.
 //! - Easily configurable with support for proxy, encryption, TLS and ZSTD compression.
 //! - Supports scanning emails for spam scores and other metrics.
+//! - **Tracing**: Optional `tracing` instrumentation of the request lifecycle (`tracing` feature).
+//! - **Metrics**: Optional client-side metrics via the `metrics` facade (`metrics` feature).
 
 // Ensure async and sync features are mutually exclusive
 #[cfg(all(feature = "async", feature = "sync"))]
@@ -21,11 +23,12 @@ compile_error!("Either 'async' or 'sync' feature must be enabled.");
 pub mod config;
 pub mod error;
 pub mod protocol;
+pub mod telemetry;
 
 pub mod backend;
 
-#[cfg(feature = "sync")]
-pub use backend::sync_client::scan_sync;
+pub use backend::controller::Controller;
+
 /// ### Synchronous Client
 ///
 /// This example demonstrates how to scan an email using the synchronous client.
@@ -46,9 +49,9 @@ pub use backend::sync_client::scan_sync;
 ///
 #[cfg(feature = "sync")]
 pub use backend::sync_client::SyncClient;
+#[cfg(feature = "sync")]
+pub use backend::sync_client::{ping_sync, scan_sync};
 
-#[cfg(feature = "async")]
-pub use backend::async_client::scan_async;
 /// ### Asynchronous Client
 ///
 /// This example demonstrates how to scan an email using the asynchronous client.
@@ -72,3 +75,5 @@ pub use backend::async_client::scan_async;
 /// ```
 #[cfg(feature = "async")]
 pub use backend::async_client::AsyncClient;
+#[cfg(feature = "async")]
+pub use backend::async_client::{ping_async, scan_async};
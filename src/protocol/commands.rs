@@ -5,6 +5,33 @@ pub enum RspamdCommand {
     Scan,
     Learnspam,
     Learnham,
+    Ping,
+    /// Controller: list of maps
+    Maps,
+    /// Controller: content of a map
+    GetMap,
+    /// Controller: save content of a map
+    SaveMap,
+    /// Controller: scan history
+    History,
+    /// Controller: clear scan history
+    HistoryReset,
+    /// Controller: save action thresholds
+    SaveActions,
+    /// Controller: save symbol scores
+    SaveSymbols,
+    /// Controller: server statistics
+    Stat,
+    /// Controller: metrics in Prometheus format
+    Metrics,
+    /// Controller: neighbours of the controller
+    Neighbours,
+    /// Controller: symbol counters
+    Counters,
+    /// Controller: check that a selector can be parsed
+    CheckSelector,
+    /// Controller: extract selector values from a message
+    CheckMessage,
 }
 
 /// Ephemeral endpoint representation
@@ -12,28 +39,59 @@ pub struct RspamdEndpoint<'a> {
     pub url: &'a str,
     pub command: RspamdCommand,
     pub need_body: bool,
+    /// Controller command that requires `enable_password` rather than `password`
+    pub privileged: bool,
+    /// Optional query string, without leading `?`
+    pub query: Option<String>,
 }
 
 /// Represents a request to the Rspamd server
 impl<'a> RspamdEndpoint<'a> {
     /// Create a new endpoint from a command
     pub fn from_command(command: RspamdCommand) -> RspamdEndpoint<'a> {
-        match command {
-            RspamdCommand::Scan => Self {
-                url: "/checkv2",
-                command,
-                need_body: true,
-            },
-            RspamdCommand::Learnspam => Self {
-                url: "/learnspam",
-                command,
-                need_body: true,
-            },
-            RspamdCommand::Learnham => Self {
-                url: "/learnham",
-                command,
-                need_body: true,
-            },
+        let (url, need_body, privileged) = match command {
+            RspamdCommand::Scan => ("/checkv2", true, false),
+            RspamdCommand::Learnspam => ("/learnspam", true, true),
+            RspamdCommand::Learnham => ("/learnham", true, true),
+            RspamdCommand::Ping => ("/ping", false, false),
+            RspamdCommand::Maps => ("/maps", false, false),
+            RspamdCommand::GetMap => ("/getmap", false, false),
+            RspamdCommand::SaveMap => ("/savemap", true, true),
+            RspamdCommand::History => ("/history", false, false),
+            RspamdCommand::HistoryReset => ("/historyreset", false, true),
+            RspamdCommand::SaveActions => ("/saveactions", true, true),
+            RspamdCommand::SaveSymbols => ("/savesymbols", true, true),
+            RspamdCommand::Stat => ("/stat", false, false),
+            RspamdCommand::Metrics => ("/metrics", false, false),
+            RspamdCommand::Neighbours => ("/neighbours", false, false),
+            RspamdCommand::Counters => ("/counters", false, false),
+            RspamdCommand::CheckSelector => ("/plugins/selectors/check_selector", false, false),
+            RspamdCommand::CheckMessage => ("/plugins/selectors/check_message", true, false),
+        };
+        Self {
+            url,
+            command,
+            need_body,
+            privileged,
+            query: None,
         }
     }
+
+    /// Whether the body is a message, which can be sent compressed.
+    /// Other bodies (maps, JSON) are used as is by the controller.
+    pub fn compressible(&self) -> bool {
+        matches!(
+            self.command,
+            RspamdCommand::Scan
+                | RspamdCommand::Learnspam
+                | RspamdCommand::Learnham
+                | RspamdCommand::CheckMessage
+        )
+    }
+
+    /// Set query string of the request
+    pub fn with_query(mut self, query: String) -> Self {
+        self.query = Some(query);
+        self
+    }
 }
@@ -0,0 +1,891 @@
+//! Replies of the controller worker
+
+use crate::error::RspamdError;
+use crate::protocol::prometheus::{MetricType, PrometheusMetrics};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use typed_builder::TypedBuilder;
+
+/// Map description as returned by `/maps`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MapInfo {
+    /// Map id used by `/getmap` and `/savemap`
+    #[serde(rename = "map")]
+    pub id: u32,
+    /// Map URI, e.g. `file:///etc/rspamd/local.d/whitelist.map`
+    pub uri: String,
+    /// Map description from the configuration
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Whether the map can be saved by the controller
+    #[serde(default)]
+    pub editable: bool,
+    /// Map type, e.g. `regexp` or `radix` (recent Rspamd versions only)
+    #[serde(default, rename = "type")]
+    pub map_type: Option<String>,
+}
+
+/// Acknowledgement of a modifying controller command
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SuccessReply {
+    pub success: bool,
+}
+
+/// Accepts either a single string or a list of strings
+fn string_or_vec<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrVec {
+        String(String),
+        Vec(Vec<String>),
+    }
+
+    Ok(match Option::<StringOrVec>::deserialize(deserializer)? {
+        None => Vec::new(),
+        Some(StringOrVec::String(s)) => vec![s],
+        Some(StringOrVec::Vec(v)) => v,
+    })
+}
+
+/// Symbol of a history row
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistorySymbol {
+    pub score: f64,
+    /// Score from the configuration, before dynamic multipliers
+    #[serde(default)]
+    pub metric_score: Option<f64>,
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+/// Scan history row
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryRow {
+    #[serde(rename = "message-id", default)]
+    pub message_id: Option<String>,
+    /// Sender from the `From` header
+    #[serde(default)]
+    pub sender_mime: Option<String>,
+    /// Sender from the SMTP envelope
+    #[serde(default)]
+    pub sender_smtp: Option<String>,
+    /// Recipients from the `To` and `Cc` headers
+    #[serde(default, deserialize_with = "string_or_vec")]
+    pub rcpt_mime: Vec<String>,
+    /// Recipients from the SMTP envelope
+    #[serde(default, deserialize_with = "string_or_vec")]
+    pub rcpt_smtp: Vec<String>,
+    #[serde(default)]
+    pub ip: Option<String>,
+    pub score: f64,
+    #[serde(default)]
+    pub required_score: Option<f64>,
+    pub action: String,
+    #[serde(default)]
+    pub symbols: HashMap<String, HistorySymbol>,
+    /// Scan time as a unix timestamp
+    pub unix_time: f64,
+    /// Scan duration in seconds
+    #[serde(default)]
+    pub time_real: Option<f64>,
+    /// Authenticated user
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub subject: Option<String>,
+    /// Message size in bytes
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+impl HistoryRow {
+    /// Whether the message was sent by or to `address`, either in headers or in the envelope
+    pub fn involves(&self, address: &str) -> bool {
+        let matches = |s: &String| s.eq_ignore_ascii_case(address);
+        self.sender_mime.iter().any(matches)
+            || self.sender_smtp.iter().any(matches)
+            || self.rcpt_mime.iter().any(matches)
+            || self.rcpt_smtp.iter().any(matches)
+    }
+}
+
+/// Page of scan history as returned by `/history`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct History {
+    #[serde(default)]
+    pub version: u32,
+    pub rows: Vec<HistoryRow>,
+    /// Total number of rows, if reported by the server (Redis history)
+    #[serde(default)]
+    pub total: Option<u64>,
+}
+
+/// Statistics file (Bayes classifier backend) from `/stat`
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatFile {
+    pub symbol: String,
+    #[serde(rename = "type")]
+    pub backend: String,
+    pub revision: u64,
+    pub used: u64,
+    pub total: u64,
+    pub size: u64,
+    pub languages: u64,
+    pub users: u64,
+}
+
+/// Server statistics as returned by `/stat`
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Stat {
+    pub version: Option<String>,
+    pub config_id: Option<String>,
+    pub uptime: u64,
+    pub read_only: bool,
+    pub scanned: u64,
+    pub learned: u64,
+    /// Number of scanned messages by action
+    pub actions: HashMap<String, u64>,
+    pub spam_count: u64,
+    pub ham_count: u64,
+    pub connections: u64,
+    pub control_connections: u64,
+    pub pools_allocated: u64,
+    pub pools_freed: u64,
+    pub bytes_allocated: u64,
+    pub chunks_allocated: u64,
+    pub shared_chunks_allocated: u64,
+    pub chunks_freed: u64,
+    pub chunks_oversized: u64,
+    pub fragmented: u64,
+    pub total_learns: u64,
+    pub statfiles: Vec<StatFile>,
+    /// Number of hashes by fuzzy storage
+    pub fuzzy_hashes: HashMap<String, u64>,
+}
+
+impl Stat {
+    /// Convert to the same representation as `/metrics`, for servers that do not support it.
+    /// Metric names follow the ones exposed by the controller.
+    pub fn to_metrics(&self) -> PrometheusMetrics {
+        use MetricType::{Counter, Gauge};
+
+        let mut metrics = PrometheusMetrics::default();
+        if let Some(version) = self.version.as_deref() {
+            metrics.push(
+                "rspamd_build_info",
+                Gauge,
+                "Rspamd version",
+                &[("version", version)],
+                1.0,
+            );
+        }
+        if let Some(config_id) = self.config_id.as_deref() {
+            metrics.push(
+                "rspamd_config",
+                Gauge,
+                "Rspamd config id",
+                &[("id", config_id)],
+                1.0,
+            );
+        }
+        let scalars = [
+            ("rspamd_uptime_seconds", Gauge, "Uptime", self.uptime),
+            (
+                "rspamd_read_only",
+                Gauge,
+                "Read only mode",
+                self.read_only as u64,
+            ),
+            (
+                "rspamd_scanned_total",
+                Counter,
+                "Scanned messages",
+                self.scanned,
+            ),
+            (
+                "rspamd_learns_total",
+                Counter,
+                "Learned messages",
+                self.learned,
+            ),
+            (
+                "rspamd_spam_total",
+                Counter,
+                "Messages classified as spam",
+                self.spam_count,
+            ),
+            (
+                "rspamd_ham_total",
+                Counter,
+                "Messages classified as ham",
+                self.ham_count,
+            ),
+            (
+                "rspamd_connections",
+                Gauge,
+                "Active connections",
+                self.connections,
+            ),
+            (
+                "rspamd_control_connections_total",
+                Counter,
+                "Control connections",
+                self.control_connections,
+            ),
+            (
+                "rspamd_pools_allocated",
+                Gauge,
+                "Allocated memory pools",
+                self.pools_allocated,
+            ),
+            (
+                "rspamd_pools_freed",
+                Gauge,
+                "Freed memory pools",
+                self.pools_freed,
+            ),
+            (
+                "rspamd_allocated_bytes",
+                Gauge,
+                "Allocated bytes",
+                self.bytes_allocated,
+            ),
+            (
+                "rspamd_chunks_allocated",
+                Gauge,
+                "Allocated memory chunks",
+                self.chunks_allocated,
+            ),
+            (
+                "rspamd_shared_chunks_allocated",
+                Gauge,
+                "Allocated shared memory chunks",
+                self.shared_chunks_allocated,
+            ),
+            (
+                "rspamd_chunks_freed",
+                Gauge,
+                "Freed memory chunks",
+                self.chunks_freed,
+            ),
+            (
+                "rspamd_chunks_oversized",
+                Gauge,
+                "Oversized memory chunks",
+                self.chunks_oversized,
+            ),
+            (
+                "rspamd_fragmented",
+                Gauge,
+                "Fragmented memory",
+                self.fragmented,
+            ),
+        ];
+        for (name, metric_type, help, value) in scalars {
+            metrics.push(name, metric_type, help, &[], value as f64);
+        }
+
+        let mut actions: Vec<_> = self.actions.iter().collect();
+        actions.sort();
+        for (action, count) in actions {
+            metrics.push(
+                "rspamd_actions_total",
+                Counter,
+                "Scanned messages by action",
+                &[("type", action)],
+                *count as f64,
+            );
+        }
+        for statfile in self.statfiles.iter() {
+            let labels = [
+                ("symbol", statfile.symbol.as_str()),
+                ("type", statfile.backend.as_str()),
+            ];
+            for (name, value) in [
+                ("rspamd_statfiles_revision", statfile.revision),
+                ("rspamd_statfiles_used", statfile.used),
+                ("rspamd_statfiles_totals", statfile.total),
+                ("rspamd_statfiles_size", statfile.size),
+                ("rspamd_statfiles_languages", statfile.languages),
+                ("rspamd_statfiles_users", statfile.users),
+            ] {
+                metrics.push(name, Gauge, "", &labels, value as f64);
+            }
+        }
+        let mut fuzzy: Vec<_> = self.fuzzy_hashes.iter().collect();
+        fuzzy.sort();
+        for (storage, hashes) in fuzzy {
+            metrics.push(
+                "rspamd_fuzzy_stat",
+                Gauge,
+                "Fuzzy hashes by storage",
+                &[("storage", storage)],
+                *hashes as f64,
+            );
+        }
+
+        metrics
+    }
+
+    /// Add statistics of another server, e.g. a neighbour in a cluster.
+    ///
+    /// Counters are summed; statistics files are matched by symbol and fuzzy storages by name,
+    /// taking the maximum for them as they are usually shared between servers.
+    pub fn merge(&mut self, other: &Stat) {
+        if self.version.is_none() {
+            self.version = other.version.clone();
+        }
+        if self.config_id.is_none() {
+            self.config_id = other.config_id.clone();
+        }
+        self.uptime = self.uptime.max(other.uptime);
+        self.read_only |= other.read_only;
+        self.scanned += other.scanned;
+        self.learned += other.learned;
+        for (action, count) in other.actions.iter() {
+            *self.actions.entry(action.clone()).or_default() += count;
+        }
+        self.spam_count += other.spam_count;
+        self.ham_count += other.ham_count;
+        self.connections += other.connections;
+        self.control_connections += other.control_connections;
+        self.pools_allocated += other.pools_allocated;
+        self.pools_freed += other.pools_freed;
+        self.bytes_allocated += other.bytes_allocated;
+        self.chunks_allocated += other.chunks_allocated;
+        self.shared_chunks_allocated += other.shared_chunks_allocated;
+        self.chunks_freed += other.chunks_freed;
+        self.chunks_oversized += other.chunks_oversized;
+        self.fragmented += other.fragmented;
+        self.total_learns += other.total_learns;
+        for statfile in other.statfiles.iter() {
+            match self
+                .statfiles
+                .iter_mut()
+                .find(|s| s.symbol == statfile.symbol)
+            {
+                Some(existing) => {
+                    existing.revision = existing.revision.max(statfile.revision);
+                    existing.used = existing.used.max(statfile.used);
+                    existing.total = existing.total.max(statfile.total);
+                    existing.size = existing.size.max(statfile.size);
+                    existing.languages = existing.languages.max(statfile.languages);
+                    existing.users = existing.users.max(statfile.users);
+                }
+                None => self.statfiles.push(statfile.clone()),
+            }
+        }
+        for (storage, hashes) in other.fuzzy_hashes.iter() {
+            let existing = self.fuzzy_hashes.entry(storage.clone()).or_default();
+            *existing = (*existing).max(*hashes);
+        }
+    }
+}
+
+/// Symbol counters as returned by `/counters`
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SymbolCounter {
+    pub symbol: String,
+    pub weight: f64,
+    /// Average number of hits per second
+    pub frequency: f64,
+    pub hits: u64,
+    /// Average execution time
+    pub time: f64,
+}
+
+/// Merge counters from several servers by symbol: hits and frequencies are summed,
+/// execution times are averaged weighted by hits. Result is sorted by symbol.
+pub fn merge_counters<I>(servers: I) -> Vec<SymbolCounter>
+where
+    I: IntoIterator<Item = Vec<SymbolCounter>>,
+{
+    let mut merged: HashMap<String, SymbolCounter> = HashMap::new();
+    for counter in servers.into_iter().flatten() {
+        match merged.get_mut(&counter.symbol) {
+            Some(existing) => {
+                let hits = existing.hits + counter.hits;
+                if hits > 0 {
+                    existing.time = (existing.time * existing.hits as f64
+                        + counter.time * counter.hits as f64)
+                        / hits as f64;
+                }
+                existing.hits = hits;
+                existing.frequency += counter.frequency;
+            }
+            None => {
+                merged.insert(counter.symbol.clone(), counter);
+            }
+        }
+    }
+    let mut merged: Vec<SymbolCounter> = merged.into_values().collect();
+    merged.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+    merged
+}
+
+/// Merge history rows from several servers, most recent first
+pub fn merge_history<I>(servers: I) -> Vec<HistoryRow>
+where
+    I: IntoIterator<Item = Vec<HistoryRow>>,
+{
+    let mut rows: Vec<HistoryRow> = servers.into_iter().flatten().collect();
+    rows.sort_by(|a, b| b.unix_time.total_cmp(&a.unix_time));
+    rows
+}
+
+/// Neighbour of a controller as configured in the `neighbours` section
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Neighbour {
+    /// Host, with or without scheme and port, e.g. `rspamd1.example.com:11334`
+    pub host: String,
+    /// Path of the controller, if it is behind a reverse proxy
+    pub path: Option<String>,
+    /// URL of the controller, reported by some Rspamd versions
+    pub url: Option<String>,
+}
+
+impl Neighbour {
+    /// Base URL of the neighbour controller; relative URLs are resolved against `base_url`
+    /// (the controller that has returned the neighbours list)
+    pub fn base_url(&self, base_url: &str) -> Result<String, RspamdError> {
+        let base = url::Url::parse(base_url)?;
+        let target = match self.url.as_deref() {
+            Some(url) if !url.is_empty() => url.to_string(),
+            _ if self.host.contains("://") => self.host.clone(),
+            _ if self.host.is_empty() => "/".to_string(),
+            _ => format!("{}://{}", base.scheme(), self.host),
+        };
+        let mut url = base.join(&target)?;
+        if let Some(path) = self.path.as_deref() {
+            url.set_path(path);
+        }
+        url.set_query(None);
+        Ok(url.to_string())
+    }
+}
+
+/// Learning status of a single classifier
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClassifierStatus {
+    pub name: String,
+    pub learned: bool,
+    /// Why the classifier has been skipped, e.g. the message was already learned
+    pub reason: Option<String>,
+}
+
+/// Accepts classifiers either as a list or as a map by name
+fn classifiers<'de, D>(deserializer: D) -> Result<Vec<ClassifierStatus>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Classifiers {
+        List(Vec<ClassifierStatus>),
+        Map(HashMap<String, ClassifierStatus>),
+    }
+
+    Ok(match Option::<Classifiers>::deserialize(deserializer)? {
+        None => Vec::new(),
+        Some(Classifiers::List(list)) => list,
+        Some(Classifiers::Map(map)) => {
+            let mut list: Vec<ClassifierStatus> = map
+                .into_iter()
+                .map(|(name, status)| ClassifierStatus { name, ..status })
+                .collect();
+            list.sort_by(|a, b| a.name.cmp(&b.name));
+            list
+        }
+    })
+}
+
+/// Reply of `/learnspam` and `/learnham`.
+///
+/// A message that has been already learned is not an error for Rspamd: `success` is false
+/// and `error` contains the reason.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LearnReply {
+    pub success: bool,
+    pub error: Option<String>,
+    /// Per-classifier status, if reported by the server or if a classifier was requested
+    #[serde(deserialize_with = "classifiers")]
+    pub classifiers: Vec<ClassifierStatus>,
+    pub scan_time: Option<f64>,
+}
+
+impl LearnReply {
+    /// Names of classifiers that have learned the message
+    pub fn learned(&self) -> impl Iterator<Item = &str> {
+        self.classifiers
+            .iter()
+            .filter(|c| c.learned)
+            .map(|c| c.name.as_str())
+    }
+
+    /// Classifiers that have been skipped, with reasons
+    pub fn skipped(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.classifiers
+            .iter()
+            .filter(|c| !c.learned)
+            .map(|c| (c.name.as_str(), c.reason.as_deref()))
+    }
+
+    /// Fill classifier status from the overall result, if the server has not reported it
+    pub(crate) fn with_classifier(mut self, classifier: Option<&str>) -> Self {
+        if let (true, Some(name)) = (self.classifiers.is_empty(), classifier) {
+            self.classifiers.push(ClassifierStatus {
+                name: name.to_string(),
+                learned: self.success,
+                reason: self.error.clone(),
+            });
+        }
+        self
+    }
+}
+
+/// Flatten selector values: a string, a number, or (nested) lists of them
+fn flatten_values(value: serde_json::Value, out: &mut Vec<String>) {
+    match value {
+        serde_json::Value::Null => {}
+        serde_json::Value::String(s) => out.push(s),
+        serde_json::Value::Array(values) => {
+            for value in values {
+                flatten_values(value, out);
+            }
+        }
+        other => out.push(other.to_string()),
+    }
+}
+
+fn selector_values<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut out = Vec::new();
+    flatten_values(serde_json::Value::deserialize(deserializer)?, &mut out);
+    Ok(out)
+}
+
+/// Reply of the selectors plugin (`/plugins/selectors/check_selector` and `check_message`)
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct SelectorReply {
+    pub success: bool,
+    /// Values extracted from the message, empty if the selector has not matched
+    #[serde(deserialize_with = "selector_values")]
+    pub data: Vec<String>,
+}
+
+/// Action thresholds for `/saveactions`, an action is disabled if its threshold is `None`
+#[derive(TypedBuilder, Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct ActionThresholds {
+    #[builder(default, setter(strip_option))]
+    pub reject: Option<f64>,
+    #[builder(default, setter(strip_option))]
+    pub rewrite_subject: Option<f64>,
+    #[builder(default, setter(strip_option))]
+    pub add_header: Option<f64>,
+    #[builder(default, setter(strip_option))]
+    pub greylist: Option<f64>,
+}
+
+impl ActionThresholds {
+    /// Thresholds from the most to the least severe action, as expected by `/saveactions`
+    pub fn to_array(&self) -> [Option<f64>; 4] {
+        [
+            self.reject,
+            self.rewrite_subject,
+            self.add_header,
+            self.greylist,
+        ]
+    }
+
+    /// Check that thresholds are finite and that enabled thresholds are strictly increasing
+    /// with action severity: `greylist < add header < rewrite subject < reject`
+    pub fn validate(&self) -> Result<(), RspamdError> {
+        const NAMES: [&str; 4] = ["reject", "rewrite subject", "add header", "greylist"];
+        let enabled: Vec<(&'static str, f64)> = NAMES
+            .into_iter()
+            .zip(self.to_array())
+            .filter_map(|(name, score)| score.map(|score| (name, score)))
+            .collect();
+
+        if let Some((name, _)) = enabled.iter().find(|(_, score)| !score.is_finite()) {
+            return Err(RspamdError::InvalidScore(name.to_string()));
+        }
+        for pair in enabled.windows(2) {
+            let (higher, higher_score) = pair[0];
+            let (lower, lower_score) = pair[1];
+            if lower_score >= higher_score {
+                return Err(RspamdError::InvalidThresholds {
+                    lower,
+                    lower_score,
+                    higher,
+                    higher_score,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct SymbolScore<'a> {
+    name: &'a str,
+    value: f64,
+}
+
+/// Body of `/savesymbols`, scores must be finite
+pub(crate) fn symbol_scores_body(scores: &HashMap<String, f64>) -> Result<Vec<u8>, RspamdError> {
+    let mut symbols: Vec<SymbolScore> = Vec::with_capacity(scores.len());
+    for (name, &value) in scores.iter() {
+        if !value.is_finite() {
+            return Err(RspamdError::InvalidScore(name.clone()));
+        }
+        symbols.push(SymbolScore { name, value });
+    }
+    symbols.sort_by(|a, b| a.name.cmp(b.name));
+    Ok(serde_json::to_vec(&symbols)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_maps_reply() {
+        let maps: Vec<MapInfo> = serde_json::from_str(
+            r#"[
+                {"map": 1, "uri": "file:///etc/rspamd/local.d/whitelist.map", "description": "Whitelist", "editable": true, "type": "radix"},
+                {"map": 2, "uri": "https://maps.rspamd.com/freemail/free.txt.zst", "editable": false}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(maps[0].id, 1);
+        assert!(maps[0].editable);
+        assert_eq!(maps[0].map_type.as_deref(), Some("radix"));
+        assert_eq!(maps[1].description, None);
+    }
+
+    #[test]
+    fn test_history_reply() {
+        let history: History = serde_json::from_str(
+            r#"{
+                "version": 2,
+                "rows": [{
+                    "message-id": "abc@example.com",
+                    "sender_mime": "user@example.com",
+                    "sender_smtp": "bounce@example.com",
+                    "rcpt_mime": ["Rcpt@example.com"],
+                    "rcpt_smtp": "rcpt@example.com",
+                    "ip": "192.0.2.1",
+                    "score": 15.5,
+                    "required_score": 15.0,
+                    "action": "reject",
+                    "symbols": {"BAYES_SPAM": {"score": 5.1, "metric_score": 5.1, "options": ["99.90%"]}},
+                    "unix_time": 1700000000.5,
+                    "time_real": 0.12,
+                    "user": "",
+                    "size": 1024
+                }],
+                "total": 1
+            }"#,
+        )
+        .unwrap();
+        let row = &history.rows[0];
+        assert_eq!(row.message_id.as_deref(), Some("abc@example.com"));
+        assert_eq!(row.rcpt_smtp, vec!["rcpt@example.com".to_string()]);
+        assert_eq!(
+            row.symbols["BAYES_SPAM"].options,
+            vec!["99.90%".to_string()]
+        );
+        assert!(row.involves("rcpt@example.com"));
+        assert!(!row.involves("other@example.com"));
+        assert_eq!(history.total, Some(1));
+    }
+
+    #[test]
+    fn test_action_thresholds() {
+        let thresholds = ActionThresholds::builder()
+            .reject(15.0)
+            .add_header(6.0)
+            .greylist(4.0)
+            .build();
+        assert!(thresholds.validate().is_ok());
+        assert_eq!(
+            serde_json::to_string(&thresholds.to_array()).unwrap(),
+            "[15.0,null,6.0,4.0]"
+        );
+
+        let thresholds = ActionThresholds::builder()
+            .reject(15.0)
+            .add_header(16.0)
+            .build();
+        match thresholds.validate() {
+            Err(RspamdError::InvalidThresholds { lower, higher, .. }) => {
+                assert_eq!(lower, "add header");
+                assert_eq!(higher, "reject");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let thresholds = ActionThresholds::builder().reject(f64::NAN).build();
+        assert!(matches!(
+            thresholds.validate(),
+            Err(RspamdError::InvalidScore(_))
+        ));
+    }
+
+    #[test]
+    fn test_symbol_scores_body() {
+        let scores = HashMap::from([("B".to_string(), 1.5), ("A".to_string(), -0.5)]);
+        assert_eq!(
+            String::from_utf8(symbol_scores_body(&scores).unwrap()).unwrap(),
+            r#"[{"name":"A","value":-0.5},{"name":"B","value":1.5}]"#
+        );
+        let scores = HashMap::from([("A".to_string(), f64::INFINITY)]);
+        assert!(symbol_scores_body(&scores).is_err());
+    }
+
+    #[test]
+    fn test_stat_to_metrics() {
+        let stat: Stat = serde_json::from_str(
+            r#"{
+                "version": "3.8.4",
+                "read_only": false,
+                "scanned": 100,
+                "learned": 5,
+                "actions": {"reject": 10, "no action": 90},
+                "statfiles": [{"revision": 3, "used": 0, "total": 0, "size": 0, "symbol": "BAYES_SPAM", "type": "redis", "languages": 0, "users": 1}],
+                "fuzzy_hashes": {"local": 42},
+                "unknown_field": 1
+            }"#,
+        )
+        .unwrap();
+        let metrics = stat.to_metrics();
+        assert_eq!(metrics.value("rspamd_scanned_total", &[]), Some(100.0));
+        assert_eq!(
+            metrics.value("rspamd_actions_total", &[("type", "reject")]),
+            Some(10.0)
+        );
+        assert_eq!(
+            metrics.value("rspamd_statfiles_revision", &[("symbol", "BAYES_SPAM")]),
+            Some(3.0)
+        );
+        assert_eq!(
+            metrics.value("rspamd_fuzzy_stat", &[("storage", "local")]),
+            Some(42.0)
+        );
+        assert_eq!(
+            metrics.get("rspamd_scanned_total").unwrap().metric_type,
+            MetricType::Counter
+        );
+        // Names ending with `_total` are counters
+        for family in metrics.families.iter() {
+            assert_eq!(
+                family.name.ends_with("_total"),
+                family.metric_type == MetricType::Counter,
+                "{}",
+                family.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_cluster_merge() {
+        let a: Stat = serde_json::from_str(
+            r#"{"scanned": 10, "actions": {"reject": 1}, "fuzzy_hashes": {"local": 5}}"#,
+        )
+        .unwrap();
+        let mut merged = Stat::default();
+        merged.merge(&a);
+        merged.merge(&a);
+        assert_eq!(merged.scanned, 20);
+        assert_eq!(merged.actions["reject"], 2);
+        assert_eq!(merged.fuzzy_hashes["local"], 5);
+
+        let counter = |hits, time| SymbolCounter {
+            symbol: "SYM".to_string(),
+            hits,
+            time,
+            frequency: 1.0,
+            ..Default::default()
+        };
+        let counters = merge_counters([vec![counter(1, 1.0)], vec![counter(3, 3.0)]]);
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0].hits, 4);
+        assert_eq!(counters[0].time, 2.5);
+        assert_eq!(counters[0].frequency, 2.0);
+    }
+
+    #[test]
+    fn test_neighbour_url() {
+        let neighbours: HashMap<String, Neighbour> = serde_json::from_str(
+            r#"{
+                "local": {"host": "localhost", "url": "/"},
+                "remote": {"host": "rspamd2.example.com:11334"},
+                "proxied": {"host": "https://proxy.example.com", "path": "/rspamd/"}
+            }"#,
+        )
+        .unwrap();
+        let base = "http://rspamd1.example.com:11334";
+        assert_eq!(
+            neighbours["local"].base_url(base).unwrap(),
+            "http://rspamd1.example.com:11334/"
+        );
+        assert_eq!(
+            neighbours["remote"].base_url(base).unwrap(),
+            "http://rspamd2.example.com:11334/"
+        );
+        assert_eq!(
+            neighbours["proxied"].base_url(base).unwrap(),
+            "https://proxy.example.com/rspamd/"
+        );
+    }
+
+    #[test]
+    fn test_learn_reply() {
+        let reply: LearnReply = serde_json::from_str(r#"{"success": true}"#).unwrap();
+        let reply = reply.with_classifier(Some("bayes_user"));
+        assert_eq!(reply.learned().collect::<Vec<_>>(), vec!["bayes_user"]);
+
+        let reply: LearnReply = serde_json::from_str(
+            r#"{"error": "<abc@example.com> has been already learned as spam, ignore it"}"#,
+        )
+        .unwrap();
+        assert!(!reply.success);
+        let reply = reply.with_classifier(Some("bayes"));
+        assert_eq!(reply.skipped().next().unwrap().0, "bayes");
+
+        let reply: LearnReply = serde_json::from_str(
+            r#"{"success": true, "classifiers": {"bayes": {"learned": true}, "bayes_user": {"learned": false, "reason": "too few tokens"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(reply.learned().collect::<Vec<_>>(), vec!["bayes"]);
+        assert_eq!(
+            reply.skipped().collect::<Vec<_>>(),
+            vec![("bayes_user", Some("too few tokens"))]
+        );
+    }
+
+    #[test]
+    fn test_selector_reply() {
+        let reply: SelectorReply =
+            serde_json::from_str(r#"{"success": true, "data": ["a", ["b", 1]]}"#).unwrap();
+        assert_eq!(reply.data, vec!["a", "b", "1"]);
+        let reply: SelectorReply =
+            serde_json::from_str(r#"{"success": true, "data": "example.com"}"#).unwrap();
+        assert_eq!(reply.data, vec!["example.com"]);
+        let reply: SelectorReply = serde_json::from_str(r#"{"success": false}"#).unwrap();
+        assert!(!reply.success && reply.data.is_empty());
+    }
+}
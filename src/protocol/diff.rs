@@ -0,0 +1,133 @@
+//! Comparison of scan replies, e.g. between a production and a canary Rspamd.
+
+use crate::protocol::RspamdScanReply;
+
+/// Scores that differ by less than this value are considered equal
+const SCORE_EPSILON: f64 = 1e-6;
+
+/// Symbol present only in one of the replies
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolDiff {
+    pub name: String,
+    pub score: f64,
+}
+
+/// Symbol present in both replies with different scores
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolScoreChange {
+    pub name: String,
+    pub old_score: f64,
+    pub new_score: f64,
+}
+
+/// Difference between two scan replies
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ReplyDiff {
+    /// Symbols present only in the new reply
+    pub added: Vec<SymbolDiff>,
+    /// Symbols present only in the old reply
+    pub removed: Vec<SymbolDiff>,
+    /// Symbols with a different score
+    pub score_changed: Vec<SymbolScoreChange>,
+    /// Old and new actions, if the action has changed
+    pub action_change: Option<(String, String)>,
+    /// New score minus old score
+    pub score_delta: f64,
+}
+
+impl ReplyDiff {
+    /// Compare two replies symbol by symbol, results are sorted by symbol name
+    pub fn new(old: &RspamdScanReply, new: &RspamdScanReply) -> Self {
+        let mut diff = ReplyDiff {
+            score_delta: new.score - old.score,
+            ..Default::default()
+        };
+
+        for (name, symbol) in new.symbols.iter() {
+            match old.symbols.get(name) {
+                None => diff.added.push(SymbolDiff {
+                    name: name.clone(),
+                    score: symbol.score,
+                }),
+                Some(old_symbol) if (old_symbol.score - symbol.score).abs() > SCORE_EPSILON => {
+                    diff.score_changed.push(SymbolScoreChange {
+                        name: name.clone(),
+                        old_score: old_symbol.score,
+                        new_score: symbol.score,
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        for (name, symbol) in old.symbols.iter() {
+            if !new.symbols.contains_key(name) {
+                diff.removed.push(SymbolDiff {
+                    name: name.clone(),
+                    score: symbol.score,
+                });
+            }
+        }
+        if old.action != new.action {
+            diff.action_change = Some((old.action.clone(), new.action.clone()));
+        }
+
+        diff.added.sort_by(|a, b| a.name.cmp(&b.name));
+        diff.removed.sort_by(|a, b| a.name.cmp(&b.name));
+        diff.score_changed.sort_by(|a, b| a.name.cmp(&b.name));
+        diff
+    }
+
+    /// True if replies have the same symbols, scores and action
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.score_changed.is_empty()
+            && self.action_change.is_none()
+            && self.score_delta.abs() <= SCORE_EPSILON
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(json: &str) -> RspamdScanReply {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_reply_diff() {
+        let old = reply(
+            r#"{"score": 5.0, "action": "no action", "symbols": {
+                "A": {"score": 1.0}, "B": {"score": 2.0}, "C": {"score": 2.0}}}"#,
+        );
+        let new = reply(
+            r#"{"score": 8.0, "action": "add header", "symbols": {
+                "A": {"score": 1.0}, "B": {"score": 3.0}, "D": {"score": 4.0}}}"#,
+        );
+        let diff = ReplyDiff::new(&old, &new);
+        assert!(!diff.is_empty());
+        assert_eq!(
+            diff.added,
+            vec![SymbolDiff {
+                name: "D".to_string(),
+                score: 4.0
+            }]
+        );
+        assert_eq!(diff.removed[0].name, "C");
+        assert_eq!(
+            diff.score_changed,
+            vec![SymbolScoreChange {
+                name: "B".to_string(),
+                old_score: 2.0,
+                new_score: 3.0
+            }]
+        );
+        assert_eq!(
+            diff.action_change,
+            Some(("no action".to_string(), "add header".to_string()))
+        );
+        assert_eq!(diff.score_delta, 3.0);
+        assert!(ReplyDiff::new(&old, &old).is_empty());
+    }
+}
@@ -5,6 +5,7 @@
 //! In general, it relies on hchacha20 for kdf and x25519 for key exchange.
 
 use crate::error::RspamdError;
+use crate::protocol::keypair::{RspamdKeypair, RspamdPublicKey};
 use blake2b_simd::blake2b;
 use chacha20::cipher::consts::U64;
 use chacha20::cipher::{KeyIvInit, StreamCipher};
@@ -19,6 +20,9 @@ use curve25519_dalek::{MontgomeryPoint, Scalar};
 use poly1305::universal_hash::KeyInit;
 use poly1305::{Poly1305, Tag};
 use rspamd_base32::{decode, encode};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::RwLock;
 
 /// It must be the same as Rspamd one, that is currently 5
 const SHORT_KEY_ID_SIZE: usize = 5;
@@ -71,14 +75,41 @@ impl RspamdSecretbox {
     }
 }
 
+/// Short key id used by Rspamd to identify a public key: a truncated blake2b hash of it
+pub fn short_key_id(pk: &[u8]) -> [u8; SHORT_KEY_ID_SIZE] {
+    let hash = blake2b(pk);
+    let mut id = [0u8; SHORT_KEY_ID_SIZE];
+    id.copy_from_slice(&hash.as_bytes()[0..SHORT_KEY_ID_SIZE]);
+    id
+}
+
 pub fn make_key_header(remote_pk: &str, local_pk: &str) -> Result<String, RspamdError> {
     let remote_pk = decode(remote_pk)
         .map_err(|_| RspamdError::EncryptionError("Base32 decode failed".to_string()))?;
-    let hash = blake2b(remote_pk.as_slice());
-    let hash_b32 = encode(&hash.as_bytes()[0..SHORT_KEY_ID_SIZE]);
+    let hash_b32 = encode(short_key_id(remote_pk.as_slice()));
     Ok(format!("{}={}", hash_b32.as_str(), local_pk))
 }
 
+/// Parses the `Key` header sent by a client (`<short key id>=<client public key>`)
+/// and returns the decoded short id of the server key and the client public key.
+pub fn parse_key_header(value: &str) -> Result<([u8; SHORT_KEY_ID_SIZE], [u8; 32]), RspamdError> {
+    let (id, pk) = value
+        .trim()
+        .split_once('=')
+        .ok_or_else(|| RspamdError::EncryptionError("Invalid Key header".to_string()))?;
+    let id: [u8; SHORT_KEY_ID_SIZE] = decode(id)
+        .map_err(|_| RspamdError::EncryptionError("Base32 decode failed".to_string()))?
+        .as_slice()
+        .try_into()
+        .map_err(|_| RspamdError::EncryptionError("Invalid key id size".to_string()))?;
+    let pk: [u8; 32] = decode(pk)
+        .map_err(|_| RspamdError::EncryptionError("Base32 decode failed".to_string()))?
+        .as_slice()
+        .try_into()
+        .map_err(|_| RspamdError::EncryptionError("Invalid public key size".to_string()))?;
+    Ok((id, pk))
+}
+
 /// Perform a scalar multiplication with a remote public key and a local secret key.
 pub(crate) fn rspamd_x25519_scalarmult(
     remote_pk: &[u8],
@@ -88,11 +119,19 @@ pub(crate) fn rspamd_x25519_scalarmult(
         .map_err(|_| RspamdError::EncryptionError("Base32 decode failed".to_string()))?
         .as_slice()
         .try_into()
-        .unwrap();
+        .map_err(|_| RspamdError::EncryptionError("Invalid public key size".to_string()))?;
+    Ok(rspamd_x25519_scalarmult_raw(&remote_pk, local_sk))
+}
+
+/// Same as `rspamd_x25519_scalarmult` but with an already decoded remote public key.
+pub(crate) fn rspamd_x25519_scalarmult_raw(
+    remote_pk: &[u8; 32],
+    local_sk: &SecretKey,
+) -> Zeroizing<MontgomeryPoint> {
     // Do manual scalarmult as Rspamd is using it's own way there
     let e = Scalar::from_bytes_mod_order(clamp_integer(local_sk.to_bytes()));
-    let p = MontgomeryPoint(remote_pk);
-    Ok(Zeroizing::new(e * p))
+    let p = MontgomeryPoint(*remote_pk);
+    Zeroizing::new(e * p)
 }
 
 /// Unlike IETF version, Rspamd uses an old suggested way to derive a shared secret - it performs
@@ -105,16 +144,10 @@ pub(crate) fn rspamd_x25519_ecdh(
     Zeroizing::new(hchacha::<U10>(&point.to_bytes().into(), &n0))
 }
 
-/// Encrypt a plaintext with a given peer public key generating an ephemeral keypair.
-fn encrypt_inplace(
-    plaintext: &[u8],
-    recipient_public_key: &[u8],
-    local_sk: &SecretKey,
-) -> Result<(Vec<u8>, RspamdNM), RspamdError> {
+/// Encrypt a plaintext with an already derived shared key using a random nonce.
+/// The output is laid out as `nonce || tag || ciphertext`.
+fn encrypt_with_nm(plaintext: &[u8], nm: &RspamdNM) -> Vec<u8> {
     let mut dest = Vec::with_capacity(plaintext.len() + 24 + poly1305::BLOCK_SIZE);
-    let ec_point = rspamd_x25519_scalarmult(recipient_public_key, local_sk)?;
-    let nm = rspamd_x25519_ecdh(ec_point);
-
     let nonce = ChaChaBox::generate_nonce(&mut OsRng);
     let cbox = RspamdSecretbox::new(nm.clone(), nonce);
     dest.extend_from_slice(nonce.as_slice());
@@ -126,22 +159,27 @@ fn encrypt_inplace(
     let tag_dest = &mut <Vec<u8> as AsMut<Vec<u8>>>::as_mut(&mut dest)
         [nonce.len()..(nonce.len() + poly1305::BLOCK_SIZE)];
     tag_dest.copy_from_slice(tag.as_slice());
-    Ok((dest, nm))
+    dest
 }
 
-pub fn httpcrypt_encrypt<T, HN, HV>(
-    url: &str,
-    body: &[u8],
-    headers: T,
-    peer_key: &[u8],
-) -> Result<HTTPCryptEncrypted, RspamdError>
+/// Encrypt a plaintext with a given peer public key generating an ephemeral keypair.
+fn encrypt_inplace(
+    plaintext: &[u8],
+    recipient_public_key: &[u8],
+    local_sk: &SecretKey,
+) -> Result<(Vec<u8>, RspamdNM), RspamdError> {
+    let ec_point = rspamd_x25519_scalarmult(recipient_public_key, local_sk)?;
+    let nm = rspamd_x25519_ecdh(ec_point);
+    Ok((encrypt_with_nm(plaintext, &nm), nm))
+}
+
+/// Serialize the inner HTTP request that is encrypted by HTTPCrypt
+fn make_inner_request<T, HN, HV>(url: &str, body: &[u8], headers: T) -> Vec<u8>
 where
     T: IntoIterator<Item = (HN, HV)>,
     HN: AsRef<[u8]>,
     HV: AsRef<[u8]>,
 {
-    let local_sk = SecretKey::generate(&mut OsRng);
-    let local_pk = local_sk.public_key();
     let extra_size = std::mem::size_of::<<ChaChaBox as AeadCore>::NonceSize>()
         + std::mem::size_of::<<ChaChaBox as AeadCore>::TagSize>();
     let mut dest = Vec::with_capacity(body.len() + 128 + extra_size);
@@ -158,6 +196,23 @@ where
     }
     dest.extend_from_slice(format!("Content-Length: {}\n\n", body.len()).as_bytes());
     dest.extend_from_slice(body.as_ref());
+    dest
+}
+
+pub fn httpcrypt_encrypt<T, HN, HV>(
+    url: &str,
+    body: &[u8],
+    headers: T,
+    peer_key: &[u8],
+) -> Result<HTTPCryptEncrypted, RspamdError>
+where
+    T: IntoIterator<Item = (HN, HV)>,
+    HN: AsRef<[u8]>,
+    HV: AsRef<[u8]>,
+{
+    let local_sk = SecretKey::generate(&mut OsRng);
+    let local_pk = local_sk.public_key();
+    let dest = make_inner_request(url, body, headers);
 
     let (encrypted, nm) = encrypt_inplace(dest.as_slice(), peer_key, &local_sk)?;
 
@@ -168,6 +223,94 @@ where
     })
 }
 
+/// Maximum number of shared secrets kept by `SharedSecretCache`
+const SHARED_SECRET_CACHE_SIZE: usize = 64;
+
+/// Cache key: local and remote public keys
+type SharedSecretKey = ([u8; 32], [u8; 32]);
+
+/// Cache of shared secrets derived between static local keypairs and remote public keys.
+/// It allows to skip scalar multiplication and hchacha derivation for each request.
+#[derive(Default)]
+pub struct SharedSecretCache {
+    entries: RwLock<HashMap<SharedSecretKey, RspamdNM>>,
+}
+
+impl SharedSecretCache {
+    /// Returns a cached shared secret or derives and caches a new one
+    pub fn get_or_derive(&self, local: &RspamdKeypair, remote: &RspamdPublicKey) -> RspamdNM {
+        let key = (*local.public_key().as_bytes(), *remote.as_bytes());
+        if let Some(nm) = self
+            .entries
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&key)
+        {
+            return nm.clone();
+        }
+
+        let nm = rspamd_x25519_ecdh(rspamd_x25519_scalarmult_raw(
+            remote.as_bytes(),
+            local.secret_key(),
+        ));
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= SHARED_SECRET_CACHE_SIZE && !entries.contains_key(&key) {
+            // Evict a single entry, remote keys rarely change so there is no need for LRU
+            if let Some(evicted) = entries.keys().next().copied() {
+                entries.remove(&evicted);
+            }
+        }
+        entries.insert(key, nm.clone());
+        nm
+    }
+
+    /// Number of cached shared secrets
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl std::fmt::Debug for SharedSecretCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedSecretCache")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// Same as `httpcrypt_encrypt` but uses a static local keypair, and the shared secret is
+/// taken from the cache. Nonces are still random for each message.
+pub fn httpcrypt_encrypt_with_keypair<T, HN, HV>(
+    url: &str,
+    body: &[u8],
+    headers: T,
+    peer_key: &[u8],
+    local_kp: &RspamdKeypair,
+    cache: &SharedSecretCache,
+) -> Result<HTTPCryptEncrypted, RspamdError>
+where
+    T: IntoIterator<Item = (HN, HV)>,
+    HN: AsRef<[u8]>,
+    HV: AsRef<[u8]>,
+{
+    let peer_key = RspamdPublicKey::from_str(
+        std::str::from_utf8(peer_key)
+            .map_err(|_| RspamdError::EncryptionError("Invalid UTF-8 in peer key".to_string()))?,
+    )?;
+    let dest = make_inner_request(url, body, headers);
+    let nm = cache.get_or_derive(local_kp, &peer_key);
+
+    Ok(HTTPCryptEncrypted {
+        body: encrypt_with_nm(dest.as_slice(), &nm),
+        peer_key: local_kp.public_key().to_string(),
+        shared_key: nm,
+    })
+}
+
 /// Decrypts body using HTTPCrypt algorithm
 pub fn httpcrypt_decrypt(body: &mut [u8], nm: RspamdNM) -> Result<usize, RspamdError> {
     if body.len() < 24 + poly1305::BLOCK_SIZE {
@@ -185,6 +328,108 @@ pub fn httpcrypt_decrypt(body: &mut [u8], nm: RspamdNM) -> Result<usize, RspamdE
     Ok(offset)
 }
 
+/// Inner HTTP request decrypted by the server side of HTTPCrypt
+pub struct HTTPCryptRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Shared key that must be used to encrypt the reply
+    pub shared_key: RspamdNM,
+}
+
+/// Server side of HTTPCrypt: matches the `Key` header against the server keypair,
+/// decrypts the body and parses the inner HTTP request.
+pub fn httpcrypt_server_decrypt(
+    key_header: &str,
+    body: &mut [u8],
+    server_sk: &SecretKey,
+) -> Result<HTTPCryptRequest, RspamdError> {
+    let (id, peer_pk) = parse_key_header(key_header)?;
+    if id != short_key_id(server_sk.public_key().as_bytes()) {
+        return Err(RspamdError::EncryptionError("Unknown key id".to_string()));
+    }
+    let nm = rspamd_x25519_ecdh(rspamd_x25519_scalarmult_raw(&peer_pk, server_sk));
+    let offset = httpcrypt_decrypt(body, nm.clone())?;
+    let decrypted = &body[offset..];
+
+    let mut hdrs = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Request::new(&mut hdrs);
+    let body_offset = match parsed
+        .parse(decrypted)
+        .map_err(|e| RspamdError::EncryptionError(e.to_string()))?
+    {
+        httparse::Status::Complete(offset) => offset,
+        httparse::Status::Partial => {
+            return Err(RspamdError::EncryptionError(
+                "Incomplete inner request".to_string(),
+            ))
+        }
+    };
+    let mut headers = Vec::with_capacity(parsed.headers.len());
+    let mut content_length = None;
+    for hdr in parsed.headers.iter() {
+        let value = std::str::from_utf8(hdr.value)?.to_string();
+        if hdr.name.eq_ignore_ascii_case("Content-Length") {
+            content_length = value.parse::<usize>().ok();
+        }
+        headers.push((hdr.name.to_string(), value));
+    }
+    let mut inner_body = &decrypted[body_offset..];
+    if let Some(len) = content_length {
+        if len > inner_body.len() {
+            return Err(RspamdError::EncryptionError(
+                "Inner request body is truncated".to_string(),
+            ));
+        }
+        inner_body = &inner_body[..len];
+    }
+
+    Ok(HTTPCryptRequest {
+        method: parsed.method.unwrap_or_default().to_string(),
+        path: parsed.path.unwrap_or_default().to_string(),
+        headers,
+        body: inner_body.to_vec(),
+        shared_key: nm,
+    })
+}
+
+/// Server side of HTTPCrypt: builds an HTTP reply and encrypts it with the shared key
+/// obtained from `httpcrypt_server_decrypt`. `Content-Length` is computed from `body`,
+/// a caller provided one is skipped.
+pub fn httpcrypt_server_encrypt<T, HN, HV>(
+    status: u16,
+    headers: T,
+    body: &[u8],
+    nm: &RspamdNM,
+) -> Vec<u8>
+where
+    T: IntoIterator<Item = (HN, HV)>,
+    HN: AsRef<[u8]>,
+    HV: AsRef<[u8]>,
+{
+    let reason = match status {
+        200..=299 => "OK",
+        400..=499 => "Client Error",
+        _ => "Server Error",
+    };
+    let mut dest = Vec::with_capacity(body.len() + 128);
+    dest.extend_from_slice(format!("HTTP/1.1 {} {}\r\n", status, reason).as_bytes());
+    for (k, v) in headers {
+        if k.as_ref().eq_ignore_ascii_case(b"Content-Length") {
+            continue;
+        }
+        dest.extend_from_slice(k.as_ref());
+        dest.extend_from_slice(b": ");
+        dest.extend_from_slice(v.as_ref());
+        dest.extend_from_slice(b"\r\n");
+    }
+    dest.extend_from_slice(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes());
+    dest.extend_from_slice(body);
+
+    encrypt_with_nm(dest.as_slice(), nm)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::protocol::encryption::*;
@@ -214,4 +459,143 @@ mod tests {
         let nm = rspamd_x25519_ecdh(point);
         assert_eq!(nm.as_slice(), &EXPECTED_NM);
     }
+
+    #[test]
+    fn test_server_roundtrip() {
+        let server_sk = SecretKey::generate(&mut OsRng);
+        let server_pk = encode(server_sk.public_key().as_bytes());
+        let body = b"From: user@example.com\n\nTest";
+
+        let encrypted = httpcrypt_encrypt(
+            "/checkv2",
+            body,
+            [("From", "user@example.com")],
+            server_pk.as_bytes(),
+        )
+        .unwrap();
+        let key_header = make_key_header(server_pk.as_str(), encrypted.peer_key.as_str()).unwrap();
+        let mut wire = encrypted.body;
+        let request = httpcrypt_server_decrypt(key_header.as_str(), &mut wire, &server_sk).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/checkv2");
+        assert!(request
+            .headers
+            .iter()
+            .any(|(k, v)| k == "From" && v == "user@example.com"));
+        assert_eq!(request.body.as_slice(), body);
+        assert_eq!(
+            request.shared_key.as_slice(),
+            encrypted.shared_key.as_slice()
+        );
+
+        // A caller provided Content-Length is replaced by the actual one
+        let mut reply = httpcrypt_server_encrypt(
+            200,
+            [
+                ("Content-Type", "application/json"),
+                ("content-length", "5"),
+            ],
+            b"{\"action\":\"no action\"}",
+            &request.shared_key,
+        );
+        let offset = httpcrypt_decrypt(&mut reply, encrypted.shared_key).unwrap();
+        let mut hdrs = [httparse::EMPTY_HEADER; 16];
+        let mut parsed = httparse::Response::new(&mut hdrs);
+        let body_offset = parsed.parse(&reply[offset..]).unwrap().unwrap();
+        assert_eq!(parsed.code, Some(200));
+        let lengths = parsed
+            .headers
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case("Content-Length"))
+            .map(|h| h.value)
+            .collect::<Vec<_>>();
+        assert_eq!(lengths, [b"22".as_slice()]);
+        assert_eq!(
+            &reply[offset + body_offset..],
+            b"{\"action\":\"no action\"}"
+        );
+    }
+
+    #[test]
+    fn test_static_keypair_roundtrip() {
+        let server = RspamdKeypair::generate();
+        let server_pk = server.public_key().to_string();
+        let client = RspamdKeypair::generate();
+        let cache = SharedSecretCache::default();
+
+        let first = httpcrypt_encrypt_with_keypair(
+            "/checkv2",
+            b"body",
+            [("From", "a")],
+            server_pk.as_bytes(),
+            &client,
+            &cache,
+        )
+        .unwrap();
+        let second = httpcrypt_encrypt_with_keypair(
+            "/checkv2",
+            b"body",
+            [("From", "a")],
+            server_pk.as_bytes(),
+            &client,
+            &cache,
+        )
+        .unwrap();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(first.peer_key, client.public_key().to_string());
+        assert_eq!(first.shared_key.as_slice(), second.shared_key.as_slice());
+        // Nonces must differ between messages
+        assert_ne!(first.body[..24], second.body[..24]);
+
+        let key_header = make_key_header(server_pk.as_str(), first.peer_key.as_str()).unwrap();
+        let mut wire = first.body;
+        let request =
+            httpcrypt_server_decrypt(key_header.as_str(), &mut wire, server.secret_key()).unwrap();
+        assert_eq!(request.body.as_slice(), b"body");
+    }
+
+    #[test]
+    fn test_shared_secret_cache_overflow() {
+        let client = RspamdKeypair::generate();
+        let cache = SharedSecretCache::default();
+        let servers = (0..SHARED_SECRET_CACHE_SIZE + 1)
+            .map(|_| RspamdKeypair::generate())
+            .collect::<Vec<_>>();
+        for server in servers.iter() {
+            cache.get_or_derive(&client, server.public_key());
+        }
+        // Only one entry is evicted on overflow
+        assert_eq!(cache.len(), SHARED_SECRET_CACHE_SIZE);
+        let last = servers.last().unwrap().public_key();
+        let key = (*client.public_key().as_bytes(), *last.as_bytes());
+        assert!(cache.entries.read().unwrap().contains_key(&key));
+    }
+
+    #[test]
+    fn test_invalid_peer_key() {
+        let client = RspamdKeypair::generate();
+        let cache = SharedSecretCache::default();
+        let result = httpcrypt_encrypt_with_keypair(
+            "/checkv2",
+            b"",
+            [("From", "a")],
+            b"\xff\xfe",
+            &client,
+            &cache,
+        );
+        assert!(
+            matches!(result, Err(RspamdError::EncryptionError(ref e)) if e.contains("Invalid UTF-8"))
+        );
+    }
+
+    #[test]
+    fn test_server_unknown_key() {
+        let server_sk = SecretKey::generate(&mut OsRng);
+        let other_pk = encode(SecretKey::generate(&mut OsRng).public_key().as_bytes());
+        let encrypted =
+            httpcrypt_encrypt("/checkv2", b"", [("From", "a")], other_pk.as_bytes()).unwrap();
+        let key_header = make_key_header(other_pk.as_str(), encrypted.peer_key.as_str()).unwrap();
+        let mut wire = encrypted.body;
+        assert!(httpcrypt_server_decrypt(key_header.as_str(), &mut wire, &server_sk).is_err());
+    }
 }
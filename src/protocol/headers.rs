@@ -0,0 +1,432 @@
+//! Generation of `X-Spam-*` headers from a scan reply, similar to `rspamc --headers` and `rspamc --mime`.
+
+use crate::protocol::RspamdScanReply;
+use std::collections::HashMap;
+use typed_builder::TypedBuilder;
+
+/// How much symbol detail is written to the symbols header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymbolDetail {
+    /// Symbol names only: `SYM1, SYM2`
+    Names,
+    /// Symbol names with scores: `SYM1(1.50), SYM2(0.00)`
+    #[default]
+    Scores,
+    /// Symbol names with scores and options: `SYM1(1.50)[opt1, opt2]`
+    Options,
+}
+
+/// Options for `X-Spam-*` headers generation, any header can be disabled by setting its name to `None`
+#[derive(TypedBuilder, Debug, Clone, PartialEq)]
+pub struct SpamHeadersOptions {
+    /// `yes` or `no` depending on the action
+    #[builder(default = Some("X-Spam".to_string()))]
+    pub spam_header: Option<String>,
+
+    /// SpamAssassin like summary: `Yes, score=7.10 required=15.00`
+    #[builder(default = Some("X-Spam-Status".to_string()))]
+    pub status_header: Option<String>,
+
+    /// Action returned by Rspamd
+    #[builder(default = Some("X-Spam-Action".to_string()))]
+    pub action_header: Option<String>,
+
+    /// Score and required score: `7.10 / 15.00`
+    #[builder(default = Some("X-Spam-Score".to_string()))]
+    pub score_header: Option<String>,
+
+    /// One star per score point
+    #[builder(default = Some("X-Spam-Level".to_string()))]
+    pub level_header: Option<String>,
+
+    /// List of symbols with the detail set by `symbol_detail`
+    #[builder(default = Some("X-Spam-Symbols".to_string()))]
+    pub symbols_header: Option<String>,
+
+    /// Amount of symbol detail
+    #[builder(default)]
+    pub symbol_detail: SymbolDetail,
+
+    /// Maximum line length for folded headers
+    #[builder(default = 78)]
+    pub fold_width: usize,
+
+    /// Also apply `milter` block from the reply (add and remove headers)
+    #[builder(default = true)]
+    pub apply_milter: bool,
+}
+
+impl Default for SpamHeadersOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Maximum number of stars in the level header
+const MAX_LEVEL: usize = 50;
+
+/// Whether an action means that the message is spam
+fn is_spam_action(action: &str) -> bool {
+    !matches!(action, "" | "no action" | "greylist" | "soft reject")
+}
+
+fn required_score(reply: &RspamdScanReply) -> f64 {
+    reply
+        .thresholds
+        .get("reject")
+        .copied()
+        .unwrap_or(reply.required_score)
+}
+
+/// Replace control characters (CR and LF in particular) with spaces: symbol options can come
+/// from the message content and must not be able to inject headers
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+fn format_symbols(reply: &RspamdScanReply, detail: SymbolDetail) -> Vec<String> {
+    let mut names: Vec<&String> = reply.symbols.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| {
+            let symbol = &reply.symbols[name];
+            match detail {
+                SymbolDetail::Names => name.clone(),
+                SymbolDetail::Scores => format!("{}({:.2})", name, symbol.score),
+                SymbolDetail::Options => match symbol.options.as_deref() {
+                    Some(options) if !options.is_empty() => format!(
+                        "{}({:.2})[{}]",
+                        name,
+                        symbol.score,
+                        sanitize(&options.join(", "))
+                    ),
+                    _ => format!("{}({:.2})", name, symbol.score),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Generate spam headers for a reply, values are not folded
+pub fn spam_headers(
+    reply: &RspamdScanReply,
+    options: &SpamHeadersOptions,
+) -> Vec<(String, String)> {
+    let is_spam = is_spam_action(reply.action.as_str());
+    let required = required_score(reply);
+    let mut headers = Vec::new();
+
+    if let Some(name) = options.spam_header.as_ref() {
+        headers.push((name.clone(), if is_spam { "yes" } else { "no" }.to_string()));
+    }
+    if let Some(name) = options.status_header.as_ref() {
+        headers.push((
+            name.clone(),
+            format!(
+                "{}, score={:.2} required={:.2}",
+                if is_spam { "Yes" } else { "No" },
+                reply.score,
+                required
+            ),
+        ));
+    }
+    if let Some(name) = options.action_header.as_ref() {
+        headers.push((name.clone(), reply.action.clone()));
+    }
+    if let Some(name) = options.score_header.as_ref() {
+        headers.push((
+            name.clone(),
+            format!("{:.2} / {:.2}", reply.score, required),
+        ));
+    }
+    if let Some(name) = options.level_header.as_ref() {
+        let stars = reply.score.max(0.0).floor() as usize;
+        headers.push((name.clone(), "*".repeat(stars.min(MAX_LEVEL))));
+    }
+    if let Some(name) = options.symbols_header.as_ref() {
+        headers.push((
+            name.clone(),
+            format_symbols(reply, options.symbol_detail).join(", "),
+        ));
+    }
+
+    headers
+}
+
+/// Fold a header so that lines do not exceed `width` where possible, breaking after commas
+/// or at whitespace. Continuation lines start with a tab. Control characters in `name` and
+/// `value` are replaced with spaces.
+pub fn fold_header(name: &str, value: &str, width: usize, newline: &str) -> String {
+    let name = sanitize(name);
+    let value = sanitize(value);
+    let mut out = format!("{}:", name);
+    let mut line_len = out.len();
+
+    for (i, token) in value.split(' ').enumerate() {
+        if token.is_empty() {
+            continue;
+        }
+        if i > 0 && line_len + 1 + token.len() > width && line_len > name.len() + 1 {
+            out.push_str(newline);
+            out.push('\t');
+            line_len = 1;
+        } else {
+            out.push(' ');
+            line_len += 1;
+        }
+        out.push_str(token);
+        line_len += token.len();
+    }
+
+    out
+}
+
+/// Render spam headers as a folded header block, each header terminated with `newline`
+pub fn format_spam_headers(
+    reply: &RspamdScanReply,
+    options: &SpamHeadersOptions,
+    newline: &str,
+) -> String {
+    let mut out = String::new();
+    for (name, value) in spam_headers(reply, options) {
+        out.push_str(&fold_header(&name, &value, options.fold_width, newline));
+        out.push_str(newline);
+    }
+    out
+}
+
+/// Finds the end of the header block, returning the offset of the body and the newline used
+fn split_headers(message: &[u8]) -> (usize, &'static str) {
+    let crlf = message.windows(4).position(|w| w == b"\r\n\r\n");
+    let lf = message.windows(2).position(|w| w == b"\n\n");
+    match (crlf, lf) {
+        (Some(c), Some(l)) if c < l => (c + 2, "\r\n"),
+        (_, Some(l)) => (l + 1, "\n"),
+        (Some(c), None) => (c + 2, "\r\n"),
+        // No body separator, the whole message is considered as headers
+        (None, None) => {
+            let newline = if message.windows(2).any(|w| w == b"\r\n") {
+                "\r\n"
+            } else {
+                "\n"
+            };
+            (message.len(), newline)
+        }
+    }
+}
+
+/// Splits a header block into raw headers, keeping continuation lines with their header
+fn raw_headers(block: &[u8]) -> Vec<&[u8]> {
+    let mut headers: Vec<&[u8]> = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+
+    while pos < block.len() {
+        let line_end = block[pos..]
+            .iter()
+            .position(|&c| c == b'\n')
+            .map_or(block.len(), |p| pos + p + 1);
+        let is_continuation = pos > 0 && matches!(block[pos], b' ' | b'\t');
+        if !is_continuation && pos > start {
+            headers.push(&block[start..pos]);
+            start = pos;
+        }
+        pos = line_end;
+    }
+    if pos > start {
+        headers.push(&block[start..pos]);
+    }
+
+    headers
+}
+
+fn header_name(raw: &[u8]) -> &[u8] {
+    raw.iter()
+        .position(|&c| c == b':')
+        .map_or(raw, |p| &raw[..p])
+        .trim_ascii()
+}
+
+/// Inject spam headers into a message, like `rspamc --mime` does.
+///
+/// If the reply contains a rewritten body, it is used instead of the original message.
+/// Existing headers with the same names as generated ones are removed, so they cannot be
+/// spoofed by the sender. Milter actions from the reply are applied if `apply_milter` is set;
+/// as in Rspamd, the index of a removed header is 0 for all occurrences, N for the Nth one and
+/// -N for the Nth one from the end.
+pub fn inject_spam_headers(
+    message: &[u8],
+    reply: &RspamdScanReply,
+    options: &SpamHeadersOptions,
+) -> Vec<u8> {
+    let message = reply.rewritten_body.as_deref().unwrap_or(message);
+    let (body_offset, newline) = split_headers(message);
+    let generated = spam_headers(reply, options);
+
+    // Lowercase name and index of the occurrence to remove
+    let mut removed: HashMap<String, i32> = generated
+        .iter()
+        .map(|(name, _)| (name.to_ascii_lowercase(), 0))
+        .collect();
+    let mut added: Vec<(String, String)> = Vec::new();
+    if options.apply_milter {
+        if let Some(milter) = reply.milter.as_ref() {
+            for (name, &index) in milter.remove_headers.iter() {
+                removed.entry(name.to_ascii_lowercase()).or_insert(index);
+            }
+            let mut milter_headers: Vec<_> = milter.add_headers.iter().collect();
+            milter_headers.sort_by_key(|(name, hdr)| (hdr.order, name.as_str()));
+            added.extend(
+                milter_headers
+                    .into_iter()
+                    .map(|(name, hdr)| (name.clone(), hdr.value.clone())),
+            );
+        }
+    }
+
+    let mut out = Vec::with_capacity(message.len() + 512);
+    for (name, value) in generated.iter().chain(added.iter()) {
+        out.extend_from_slice(fold_header(name, value, options.fold_width, newline).as_bytes());
+        out.extend_from_slice(newline.as_bytes());
+    }
+    let raw = raw_headers(&message[..body_offset])
+        .into_iter()
+        .map(|raw| {
+            let name = String::from_utf8_lossy(header_name(raw)).to_ascii_lowercase();
+            (name, raw)
+        })
+        .collect::<Vec<_>>();
+    let mut totals: HashMap<&str, i32> = HashMap::new();
+    for (name, _) in raw.iter() {
+        *totals.entry(name.as_str()).or_default() += 1;
+    }
+    let mut seen: HashMap<&str, i32> = HashMap::new();
+    for (name, raw) in raw.iter() {
+        let nth = seen.entry(name.as_str()).or_default();
+        *nth += 1;
+        let remove = match removed.get(name) {
+            None => false,
+            Some(0) => true,
+            Some(&index) if index > 0 => *nth == index,
+            Some(&index) => *nth == totals[name.as_str()] + index + 1,
+        };
+        if !remove {
+            out.extend_from_slice(raw);
+        }
+    }
+    out.extend_from_slice(&message[body_offset..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply() -> RspamdScanReply {
+        serde_json::from_str(
+            r#"{
+                "score": 7.5,
+                "required_score": 15.0,
+                "action": "add header",
+                "symbols": {
+                    "R_SPF_FAIL": {"name": "R_SPF_FAIL", "score": 1.5, "options": ["-all"]},
+                    "BAYES_SPAM": {"name": "BAYES_SPAM", "score": 6.0}
+                },
+                "milter": {
+                    "add_headers": {"X-Virus": {"value": "no", "order": 0}},
+                    "remove_headers": {"X-Old": 1}
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_spam_headers() {
+        let headers = spam_headers(&reply(), &SpamHeadersOptions::default());
+        assert!(headers.contains(&("X-Spam".to_string(), "yes".to_string())));
+        assert!(headers.contains(&(
+            "X-Spam-Status".to_string(),
+            "Yes, score=7.50 required=15.00".to_string()
+        )));
+        assert!(headers.contains(&("X-Spam-Level".to_string(), "*******".to_string())));
+        assert!(headers.contains(&(
+            "X-Spam-Symbols".to_string(),
+            "BAYES_SPAM(6.00), R_SPF_FAIL(1.50)".to_string()
+        )));
+    }
+
+    #[test]
+    fn test_fold_header() {
+        let folded = fold_header(
+            "X-Spam-Symbols",
+            &["SYMBOL_NAME(1.00),"; 10].join(" "),
+            40,
+            "\n",
+        );
+        assert!(folded.lines().all(|l| l.len() <= 40));
+        assert!(folded.lines().skip(1).all(|l| l.starts_with('\t')));
+    }
+
+    #[test]
+    fn test_inject_spam_headers() {
+        let message = b"From: a@example.com\r\nX-Spam: no\r\nX-Old: 1\r\nSubject: test\r\n  continued\r\n\r\nBody\r\n";
+        let options = SpamHeadersOptions::builder()
+            .symbol_detail(SymbolDetail::Options)
+            .build();
+        let out = String::from_utf8(inject_spam_headers(message, &reply(), &options)).unwrap();
+        assert!(out.starts_with("X-Spam: yes\r\n"));
+        assert!(out.contains("X-Spam-Symbols: BAYES_SPAM(6.00), R_SPF_FAIL(1.50)[-all]\r\n"));
+        assert!(out.contains("X-Virus: no\r\n"));
+        assert!(out.contains("Subject: test\r\n  continued\r\n\r\nBody\r\n"));
+        assert!(!out.contains("X-Old"));
+        assert!(!out.contains("X-Spam: no"));
+    }
+
+    #[test]
+    fn test_injection() {
+        let mut reply = reply();
+        reply.symbols.get_mut("R_SPF_FAIL").unwrap().options =
+            Some(vec!["-all\r\nX-Injected: yes".to_string()]);
+        reply.milter.as_mut().unwrap().add_headers.insert(
+            "X-Milter".to_string(),
+            serde_json::from_str(r#"{"value": "a\nX-Injected: yes", "order": 1}"#).unwrap(),
+        );
+        let options = SpamHeadersOptions::builder()
+            .symbol_detail(SymbolDetail::Options)
+            .build();
+        let out = inject_spam_headers(b"Subject: test\r\n\r\nBody", &reply, &options);
+        let out = String::from_utf8(out).unwrap();
+        assert!(!out.contains("\nX-Injected"), "{}", out);
+        assert!(!out.contains("\r\nX-Injected"), "{}", out);
+        let headers = spam_headers(&reply, &options);
+        assert!(headers
+            .iter()
+            .any(|(_, v)| v.ends_with("R_SPF_FAIL(1.50)[-all  X-Injected: yes]")));
+        assert!(out.contains("X-Milter: a X-Injected: yes\r\n"));
+    }
+
+    #[test]
+    fn test_remove_headers_index() {
+        let message =
+            b"X-Old: 1\nX-Old: 2\nX-Old: 3\nX-Last: 1\nX-Last: 2\nX-All: 1\nX-All: 2\n\nBody";
+        let mut reply = reply();
+        reply.milter.as_mut().unwrap().remove_headers = HashMap::from([
+            ("X-Old".to_string(), 2),
+            ("X-Last".to_string(), -1),
+            ("x-all".to_string(), 0),
+        ]);
+        let out = inject_spam_headers(message, &reply, &SpamHeadersOptions::default());
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains("X-Old: 1\nX-Old: 3\nX-Last: 1\n\nBody"),
+            "{}",
+            out
+        );
+        assert!(!out.contains("X-All"));
+    }
+}
@@ -0,0 +1,537 @@
+//! Keypairs compatible with Rspamd keypair format.
+//!
+//! Rspamd stores keypairs as UCL (or JSON) objects like the following one, as produced by
+//! `rspamadm keypair`:
+//!
+//! ```text
+//! keypair {
+//!     privkey = "oqqm9kkt7c1ws638cyf41apar3in1wuyx647gzrx88hhd94ehm3y";
+//!     id = "onztu3dmoms7i7panf5mdc6hqfb3dxore8etfpftmkcy85e6jr6pujn4fgskukjfa868oceoun485rcfrywk8ihug6g1i3b8g8aj8ay";
+//!     pubkey = "k4nz984k36xmcynm1hr9kdbn6jhcxf4ggbrb1quay7f88rpm9kay";
+//!     type = "kex";
+//!     algorithm = "curve25519";
+//!     encoding = "base32";
+//! }
+//! ```
+//!
+//! Only `kex` keypairs using `curve25519` are supported, as those are the ones used by HTTPCrypt.
+
+use crate::error::RspamdError;
+use crate::protocol::encryption::short_key_id;
+use blake2b_simd::blake2b;
+use crypto_box::{aead::OsRng, SecretKey};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Encoding used for keys in Rspamd configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyEncoding {
+    #[default]
+    Base32,
+    Hex,
+}
+
+impl KeyEncoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            KeyEncoding::Base32 => "base32",
+            KeyEncoding::Hex => "hex",
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> String {
+        match self {
+            KeyEncoding::Base32 => rspamd_base32::encode(data),
+            KeyEncoding::Hex => data.iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+
+    fn decode(&self, data: &str) -> Result<Vec<u8>, RspamdError> {
+        match self {
+            KeyEncoding::Base32 => rspamd_base32::decode(data)
+                .map_err(|_| RspamdError::EncryptionError("Base32 decode failed".to_string())),
+            KeyEncoding::Hex => {
+                if !data.len().is_multiple_of(2) {
+                    return Err(RspamdError::EncryptionError(
+                        "Hex decode failed".to_string(),
+                    ));
+                }
+                (0..data.len())
+                    .step_by(2)
+                    .map(|i| {
+                        data.get(i..i + 2)
+                            .and_then(|s| u8::from_str_radix(s, 16).ok())
+                            .ok_or_else(|| {
+                                RspamdError::EncryptionError("Hex decode failed".to_string())
+                            })
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    fn decode_key(&self, data: &str) -> Result<[u8; 32], RspamdError> {
+        self.decode(data)?
+            .as_slice()
+            .try_into()
+            .map_err(|_| RspamdError::EncryptionError("Invalid key size".to_string()))
+    }
+}
+
+impl FromStr for KeyEncoding {
+    type Err = RspamdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "base32" => Ok(KeyEncoding::Base32),
+            "hex" => Ok(KeyEncoding::Hex),
+            _ => Err(RspamdError::EncryptionError(format!(
+                "Unsupported key encoding: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Rspamd curve25519 public key
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RspamdPublicKey {
+    pk: [u8; 32],
+}
+
+impl RspamdPublicKey {
+    /// Create a public key from raw bytes
+    pub fn from_bytes(pk: [u8; 32]) -> Self {
+        Self { pk }
+    }
+
+    /// Parse a public key using the specified encoding
+    pub fn decode(data: &str, encoding: KeyEncoding) -> Result<Self, RspamdError> {
+        Ok(Self {
+            pk: encoding.decode_key(data.trim())?,
+        })
+    }
+
+    /// Encode a public key using the specified encoding
+    pub fn encode(&self, encoding: KeyEncoding) -> String {
+        encoding.encode(&self.pk)
+    }
+
+    /// Raw public key bytes
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.pk
+    }
+
+    /// Full key id as used by Rspamd (blake2b hash of the public key)
+    pub fn id(&self) -> [u8; 64] {
+        let mut id = [0u8; 64];
+        id.copy_from_slice(blake2b(&self.pk).as_bytes());
+        id
+    }
+
+    /// Short key id that is sent in the `Key` header of HTTPCrypt requests
+    pub fn short_id(&self) -> [u8; 5] {
+        short_key_id(&self.pk)
+    }
+}
+
+/// Parses a base32 encoded public key, which is the default Rspamd format
+impl FromStr for RspamdPublicKey {
+    type Err = RspamdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::decode(s, KeyEncoding::Base32)
+    }
+}
+
+impl fmt::Display for RspamdPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.encode(KeyEncoding::Base32))
+    }
+}
+
+impl fmt::Debug for RspamdPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RspamdPublicKey({})", self)
+    }
+}
+
+impl Serialize for RspamdPublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for RspamdPublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Rspamd curve25519 `kex` keypair
+#[derive(Clone, PartialEq)]
+pub struct RspamdKeypair {
+    sk: SecretKey,
+    pk: RspamdPublicKey,
+}
+
+/// On-disk representation of a keypair, shared by UCL and JSON formats
+#[derive(Serialize, Deserialize)]
+struct KeypairRepr {
+    privkey: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pubkey: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    algorithm: Option<String>,
+    #[serde(default)]
+    encoding: KeyEncoding,
+}
+
+/// Keypairs can be either bare objects or wrapped into `keypair` object
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeypairFile {
+    Wrapped { keypair: KeypairRepr },
+    Bare(KeypairRepr),
+}
+
+impl RspamdKeypair {
+    /// Generate a new random keypair
+    pub fn generate() -> Self {
+        Self::from_secret_key(SecretKey::generate(&mut OsRng))
+    }
+
+    /// Create a keypair from a secret key, deriving the public key
+    pub fn from_secret_key(sk: SecretKey) -> Self {
+        let pk = RspamdPublicKey::from_bytes(*sk.public_key().as_bytes());
+        Self { sk, pk }
+    }
+
+    /// Create a keypair from the encoded secret key only
+    pub fn from_secret(privkey: &str, encoding: KeyEncoding) -> Result<Self, RspamdError> {
+        Ok(Self::from_secret_key(SecretKey::from_bytes(
+            encoding.decode_key(privkey.trim())?,
+        )))
+    }
+
+    /// Public part of the keypair
+    pub fn public_key(&self) -> &RspamdPublicKey {
+        &self.pk
+    }
+
+    /// Secret part of the keypair
+    pub fn secret_key(&self) -> &SecretKey {
+        &self.sk
+    }
+
+    /// Full key id as used by Rspamd (blake2b hash of the public key)
+    pub fn id(&self) -> [u8; 64] {
+        self.pk.id()
+    }
+
+    /// Parse a keypair either in UCL or in JSON format
+    pub fn parse(input: &str) -> Result<Self, RspamdError> {
+        if input.trim_start().starts_with('{') {
+            Self::from_json(input)
+        } else {
+            Self::from_ucl(input)
+        }
+    }
+
+    /// Parse a keypair in JSON format
+    pub fn from_json(input: &str) -> Result<Self, RspamdError> {
+        match serde_json::from_str::<KeypairFile>(input)? {
+            KeypairFile::Wrapped { keypair } | KeypairFile::Bare(keypair) => {
+                Self::from_repr(keypair)
+            }
+        }
+    }
+
+    /// Parse a keypair in UCL format, as used in Rspamd configuration files
+    pub fn from_ucl(input: &str) -> Result<Self, RspamdError> {
+        let mut fields = parse_ucl_keypair(input)?;
+        let privkey = fields
+            .remove("privkey")
+            .ok_or_else(|| RspamdError::EncryptionError("Missing privkey".to_string()))?;
+        let encoding = fields
+            .remove("encoding")
+            .map(|e| e.parse())
+            .transpose()?
+            .unwrap_or_default();
+        Self::from_repr(KeypairRepr {
+            privkey,
+            pubkey: fields.remove("pubkey"),
+            id: fields.remove("id"),
+            kind: fields.remove("type"),
+            algorithm: fields.remove("algorithm"),
+            encoding,
+        })
+    }
+
+    /// Emit a keypair in Rspamd UCL format
+    pub fn to_ucl(&self, encoding: KeyEncoding) -> String {
+        let mut out = String::from("keypair {\n");
+        for (k, v) in self.fields(encoding) {
+            out.push_str(&format!("    {} = \"{}\";\n", k, v));
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Emit a keypair in Rspamd JSON format
+    pub fn to_json(&self, encoding: KeyEncoding) -> String {
+        let keypair: serde_json::Map<String, serde_json::Value> = self
+            .fields(encoding)
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), serde_json::Value::String(v)))
+            .collect();
+        serde_json::json!({ "keypair": keypair }).to_string()
+    }
+
+    fn fields(&self, encoding: KeyEncoding) -> Vec<(&'static str, String)> {
+        vec![
+            ("pubkey", self.pk.encode(encoding)),
+            ("privkey", encoding.encode(&self.sk.to_bytes())),
+            ("id", encoding.encode(&self.id())),
+            ("encoding", encoding.as_str().to_string()),
+            ("algorithm", "curve25519".to_string()),
+            ("type", "kex".to_string()),
+        ]
+    }
+
+    fn from_repr(repr: KeypairRepr) -> Result<Self, RspamdError> {
+        if let Some(kind) = repr.kind.as_deref() {
+            if kind != "kex" {
+                return Err(RspamdError::EncryptionError(format!(
+                    "Unsupported keypair type: {}",
+                    kind
+                )));
+            }
+        }
+        if let Some(algorithm) = repr.algorithm.as_deref() {
+            if algorithm != "curve25519" {
+                return Err(RspamdError::EncryptionError(format!(
+                    "Unsupported keypair algorithm: {}",
+                    algorithm
+                )));
+            }
+        }
+        let keypair = Self::from_secret(&repr.privkey, repr.encoding)?;
+        if let Some(pubkey) = repr.pubkey.as_deref() {
+            if RspamdPublicKey::decode(pubkey, repr.encoding)? != keypair.pk {
+                return Err(RspamdError::EncryptionError(
+                    "Public key does not match private key".to_string(),
+                ));
+            }
+        }
+        if let Some(id) = repr.id.as_deref() {
+            if repr.encoding.decode(id.trim())? != keypair.id() {
+                return Err(RspamdError::EncryptionError(
+                    "Key id does not match public key".to_string(),
+                ));
+            }
+        }
+        Ok(keypair)
+    }
+}
+
+impl fmt::Debug for RspamdKeypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the secret key
+        f.debug_struct("RspamdKeypair")
+            .field("pubkey", &self.pk)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for RspamdKeypair {
+    type Err = RspamdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl Serialize for RspamdKeypair {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut fields: HashMap<&str, String> =
+            self.fields(KeyEncoding::Base32).into_iter().collect();
+        KeypairRepr {
+            privkey: fields.remove("privkey").unwrap_or_default(),
+            pubkey: fields.remove("pubkey"),
+            id: fields.remove("id"),
+            kind: fields.remove("type"),
+            algorithm: fields.remove("algorithm"),
+            encoding: KeyEncoding::Base32,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RspamdKeypair {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match KeypairFile::deserialize(deserializer)? {
+            KeypairFile::Wrapped { keypair } | KeypairFile::Bare(keypair) => {
+                Self::from_repr(keypair).map_err(serde::de::Error::custom)
+            }
+        }
+    }
+}
+
+/// Minimal UCL parser that extracts string fields from a (possibly wrapped) keypair object
+fn parse_ucl_keypair(input: &str) -> Result<HashMap<String, String>, RspamdError> {
+    let tokens = tokenize_ucl(input)?;
+    let mut fields = HashMap::new();
+    let mut pos = 0;
+
+    while pos < tokens.len() {
+        match (&tokens[pos], tokens.get(pos + 1), tokens.get(pos + 2)) {
+            // Object opening, flatten its content as we only care about a single keypair
+            (UclToken::Word(_), Some(UclToken::Open), _) => pos += 2,
+            (UclToken::Word(_), Some(UclToken::Assign), Some(UclToken::Open)) => pos += 3,
+            (UclToken::Open | UclToken::Close | UclToken::Separator, _, _) => pos += 1,
+            (UclToken::Word(key), Some(UclToken::Assign), Some(UclToken::Word(value))) => {
+                fields.insert(key.clone(), value.clone());
+                pos += 3;
+            }
+            (UclToken::Word(key), Some(UclToken::Word(value)), _) => {
+                fields.insert(key.clone(), value.clone());
+                pos += 2;
+            }
+            _ => {
+                return Err(RspamdError::EncryptionError(
+                    "Invalid UCL keypair".to_string(),
+                ))
+            }
+        }
+    }
+
+    Ok(fields)
+}
+
+#[derive(Debug, PartialEq)]
+enum UclToken {
+    Word(String),
+    Assign,
+    Separator,
+    Open,
+    Close,
+}
+
+fn tokenize_ucl(input: &str) -> Result<Vec<UclToken>, RspamdError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '=' | ':' => tokens.push(UclToken::Assign),
+            ';' | ',' => tokens.push(UclToken::Separator),
+            '{' => tokens.push(UclToken::Open),
+            '}' => tokens.push(UclToken::Close),
+            '"' | '\'' => {
+                let mut word = String::new();
+                let mut closed = false;
+                while let Some(n) = chars.next() {
+                    match n {
+                        '\\' => {
+                            if let Some(escaped) = chars.next() {
+                                word.push(escaped);
+                            }
+                        }
+                        n if n == c => {
+                            closed = true;
+                            break;
+                        }
+                        n => word.push(n),
+                    }
+                }
+                if !closed {
+                    return Err(RspamdError::EncryptionError(
+                        "Unterminated string in UCL keypair".to_string(),
+                    ));
+                }
+                tokens.push(UclToken::Word(word));
+            }
+            c => {
+                let mut word = String::from(c);
+                while let Some(&n) = chars.peek() {
+                    if n.is_whitespace() || "=:;,{}#\"'".contains(n) {
+                        break;
+                    }
+                    word.push(n);
+                    chars.next();
+                }
+                tokens.push(UclToken::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The same keypair is used in tests/rspamd-config
+    const TEST_KEYPAIR: &str = r#"
+keypair {
+    privkey = "oqqm9kkt7c1ws638cyf41apar3in1wuyx647gzrx88hhd94ehm3y";
+    id = "onztu3dmoms7i7panf5mdc6hqfb3dxore8etfpftmkcy85e6jr6pujn4fgskukjfa868oceoun485rcfrywk8ihug6g1i3b8g8aj8ay";
+    pubkey = "k4nz984k36xmcynm1hr9kdbn6jhcxf4ggbrb1quay7f88rpm9kay";
+    type = "kex";
+    algorithm = "curve25519";
+    encoding = "base32";
+}
+"#;
+
+    #[test]
+    fn test_parse_ucl() {
+        let kp = RspamdKeypair::parse(TEST_KEYPAIR).unwrap();
+        assert_eq!(
+            kp.public_key().to_string(),
+            "k4nz984k36xmcynm1hr9kdbn6jhcxf4ggbrb1quay7f88rpm9kay"
+        );
+        assert_eq!(
+            rspamd_base32::encode(kp.id()),
+            "onztu3dmoms7i7panf5mdc6hqfb3dxore8etfpftmkcy85e6jr6pujn4fgskukjfa868oceoun485rcfrywk8ihug6g1i3b8g8aj8ay"
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_formats() {
+        let kp = RspamdKeypair::generate();
+        for encoding in [KeyEncoding::Base32, KeyEncoding::Hex] {
+            assert_eq!(RspamdKeypair::parse(&kp.to_ucl(encoding)).unwrap(), kp);
+            assert_eq!(RspamdKeypair::parse(&kp.to_json(encoding)).unwrap(), kp);
+        }
+        let json = serde_json::to_string(&kp).unwrap();
+        assert_eq!(serde_json::from_str::<RspamdKeypair>(&json).unwrap(), kp);
+    }
+
+    #[test]
+    fn test_mismatched_pubkey() {
+        let other = RspamdKeypair::generate();
+        let input = TEST_KEYPAIR.replace(
+            "k4nz984k36xmcynm1hr9kdbn6jhcxf4ggbrb1quay7f88rpm9kay",
+            &other.public_key().to_string(),
+        );
+        assert!(RspamdKeypair::parse(&input).is_err());
+    }
+}
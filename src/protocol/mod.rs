@@ -1,5 +1,11 @@
 pub mod commands;
+pub mod controller;
+pub mod diff;
 pub mod encryption;
+pub mod headers;
+pub mod keypair;
+pub mod prometheus;
 pub mod scan;
 
+pub use keypair::{KeyEncoding, RspamdKeypair, RspamdPublicKey};
 pub use scan::RspamdScanReply;
@@ -0,0 +1,301 @@
+//! Parser of the Prometheus text exposition format, as used by the controller's `/metrics`.
+
+use crate::error::RspamdError;
+use std::collections::BTreeMap;
+
+/// Type of a metric family, from `# TYPE` lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+    Summary,
+    #[default]
+    Untyped,
+}
+
+impl MetricType {
+    fn parse(s: &str) -> Self {
+        match s {
+            "counter" => MetricType::Counter,
+            "gauge" => MetricType::Gauge,
+            "histogram" => MetricType::Histogram,
+            "summary" => MetricType::Summary,
+            _ => MetricType::Untyped,
+        }
+    }
+}
+
+/// Single sample of a metric family
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Sample name, it can differ from the family name for histograms and summaries
+    /// (e.g. `_bucket`, `_sum` and `_count` suffixes)
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+    /// Optional timestamp in milliseconds
+    pub timestamp: Option<i64>,
+}
+
+impl Sample {
+    /// Value of a label
+    pub fn label(&self, name: &str) -> Option<&str> {
+        self.labels.get(name).map(String::as_str)
+    }
+}
+
+/// Metric family: samples sharing the same name, type and help
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MetricFamily {
+    pub name: String,
+    pub help: Option<String>,
+    pub metric_type: MetricType,
+    pub samples: Vec<Sample>,
+}
+
+/// Parsed metrics, families are kept in the order they appear
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PrometheusMetrics {
+    pub families: Vec<MetricFamily>,
+}
+
+impl PrometheusMetrics {
+    /// Parse metrics in the Prometheus text format
+    pub fn parse(text: &str) -> Result<Self, RspamdError> {
+        let mut metrics = PrometheusMetrics::default();
+
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(comment) = line.strip_prefix('#') {
+                let mut parts = comment.trim_start().splitn(3, ' ');
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some("HELP"), Some(name), help) => {
+                        metrics.family_mut(name).help = Some(unescape(help.unwrap_or(""), false));
+                    }
+                    (Some("TYPE"), Some(name), Some(metric_type)) => {
+                        metrics.family_mut(name).metric_type =
+                            MetricType::parse(metric_type.trim());
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
+            let sample = parse_sample(line).ok_or_else(|| {
+                RspamdError::MetricsParseError(format!("line {}: {}", lineno + 1, line))
+            })?;
+            let family = metrics.family_name(&sample.name).to_string();
+            metrics.family_mut(&family).samples.push(sample);
+        }
+
+        Ok(metrics)
+    }
+
+    /// Metric family by name
+    pub fn get(&self, name: &str) -> Option<&MetricFamily> {
+        self.families.iter().find(|f| f.name == name)
+    }
+
+    /// Value of the first sample of a family matching all `labels`
+    pub fn value(&self, name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+        self.get(name)?
+            .samples
+            .iter()
+            .find(|s| labels.iter().all(|(k, v)| s.label(k) == Some(*v)))
+            .map(|s| s.value)
+    }
+
+    /// Add a sample to a family, creating the family if needed
+    pub fn push(
+        &mut self,
+        name: &str,
+        metric_type: MetricType,
+        help: &str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        let family = self.family_mut(name);
+        family.metric_type = metric_type;
+        if family.help.is_none() && !help.is_empty() {
+            family.help = Some(help.to_string());
+        }
+        family.samples.push(Sample {
+            name: name.to_string(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            value,
+            timestamp: None,
+        });
+    }
+
+    /// Family of a sample: histogram and summary samples have suffixes
+    fn family_name<'a>(&self, sample_name: &'a str) -> &'a str {
+        if self.get(sample_name).is_some() {
+            return sample_name;
+        }
+        for suffix in ["_bucket", "_sum", "_count"] {
+            if let Some(base) = sample_name.strip_suffix(suffix) {
+                if self.get(base).is_some_and(|f| {
+                    matches!(f.metric_type, MetricType::Histogram | MetricType::Summary)
+                }) {
+                    return base;
+                }
+            }
+        }
+        sample_name
+    }
+
+    fn family_mut(&mut self, name: &str) -> &mut MetricFamily {
+        let pos = match self.families.iter().position(|f| f.name == name) {
+            Some(pos) => pos,
+            None => {
+                self.families.push(MetricFamily {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+                self.families.len() - 1
+            }
+        };
+        &mut self.families[pos]
+    }
+}
+
+/// Unescape help text (`\\` and `\n`) or label values (also `\"`)
+fn unescape(s: &str, quotes: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('\\') => out.push('\\'),
+            Some('"') if quotes => out.push('"'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+fn parse_value(s: &str) -> Option<f64> {
+    match s {
+        "+Inf" | "Inf" => Some(f64::INFINITY),
+        "-Inf" => Some(f64::NEG_INFINITY),
+        "NaN" => Some(f64::NAN),
+        _ => s.parse().ok(),
+    }
+}
+
+/// Parse `name{label="value",...} value [timestamp]`
+fn parse_sample(line: &str) -> Option<Sample> {
+    let name_end = line
+        .find(|c: char| c == '{' || c.is_ascii_whitespace())
+        .unwrap_or(line.len());
+    let name = &line[..name_end];
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut labels = BTreeMap::new();
+    let mut rest = &line[name_end..];
+    if let Some(mut inner) = rest.strip_prefix('{') {
+        loop {
+            inner = inner.trim_start_matches([' ', ',']);
+            if let Some(after) = inner.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+            let eq = inner.find('=')?;
+            let label = inner[..eq].trim();
+            let value_start = inner[eq + 1..].trim_start().strip_prefix('"')?;
+            // Find the closing quote, skipping escaped characters
+            let mut escaped = false;
+            let end = value_start.char_indices().find_map(|(i, c)| {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    return Some(i);
+                }
+                None
+            })?;
+            labels.insert(label.to_string(), unescape(&value_start[..end], true));
+            inner = &value_start[end + 1..];
+        }
+    }
+
+    let mut fields = rest.split_ascii_whitespace();
+    let value = parse_value(fields.next()?)?;
+    let timestamp = match fields.next() {
+        Some(ts) => Some(ts.parse().ok()?),
+        None => None,
+    };
+
+    Some(Sample {
+        name: name.to_string(),
+        labels,
+        value,
+        timestamp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = r#"# HELP rspamd_build_info A metric with a constant '1' value labeled by version.
+# TYPE rspamd_build_info gauge
+rspamd_build_info{version="3.8.4"} 1
+# HELP rspamd_scanned_total Scanned messages.
+# TYPE rspamd_scanned_total counter
+rspamd_scanned_total 1234
+# TYPE rspamd_actions_total counter
+rspamd_actions_total{type="reject"} 10
+rspamd_actions_total{type="no action", note="a \"quoted\" \\ value"} 1200 1700000000000
+# TYPE scan_time_seconds histogram
+scan_time_seconds_bucket{le="0.1"} 5
+scan_time_seconds_bucket{le="+Inf"} 7
+scan_time_seconds_sum 0.5
+scan_time_seconds_count 7
+"#;
+        let metrics = PrometheusMetrics::parse(text).unwrap();
+        let build = metrics.get("rspamd_build_info").unwrap();
+        assert_eq!(build.metric_type, MetricType::Gauge);
+        assert_eq!(build.samples[0].label("version"), Some("3.8.4"));
+        assert_eq!(metrics.value("rspamd_scanned_total", &[]), Some(1234.0));
+        assert_eq!(
+            metrics.value("rspamd_actions_total", &[("type", "no action")]),
+            Some(1200.0)
+        );
+        let actions = metrics.get("rspamd_actions_total").unwrap();
+        assert_eq!(
+            actions.samples[1].label("note"),
+            Some(r#"a "quoted" \ value"#)
+        );
+        assert_eq!(actions.samples[1].timestamp, Some(1700000000000));
+        let histogram = metrics.get("scan_time_seconds").unwrap();
+        assert_eq!(histogram.samples.len(), 4);
+        assert_eq!(histogram.samples[1].label("le"), Some("+Inf"));
+        assert_eq!(metrics.families.len(), 4);
+
+        assert!(PrometheusMetrics::parse("metric{label=\"x} 1").is_err());
+        let e = PrometheusMetrics::parse("metric one").unwrap_err();
+        assert!(matches!(e, RspamdError::MetricsParseError(_)));
+        assert_eq!(e.to_string(), "Metrics parsing error: line 1: metric one");
+    }
+}
@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 /// Rspamd scan reply structure
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RspamdScanReply {
     /// If message has been skipped
     #[serde(default)]
@@ -49,10 +49,43 @@ pub struct RspamdScanReply {
     /// This field is not part of the JSON response but is extracted from the response body
     #[serde(skip)]
     pub rewritten_body: Option<Vec<u8>>,
+    /// Reason of a fallback verdict: set when the reply has been synthesized by the client
+    /// because the server could not be reached, see `Config::fallback`
+    #[serde(skip)]
+    pub fallback: Option<String>,
+}
+
+impl RspamdScanReply {
+    /// Synthetic reply with `action`, used instead of an error when the server could not be reached
+    pub fn fallback(action: &str, reason: String) -> Self {
+        Self {
+            is_skipped: false,
+            score: 0.0,
+            required_score: 0.0,
+            action: action.to_string(),
+            thresholds: HashMap::new(),
+            symbols: HashMap::new(),
+            messages: HashMap::new(),
+            urls: Vec::new(),
+            emails: Vec::new(),
+            message_id: String::new(),
+            time_real: 0.0,
+            milter: None,
+            filename: String::new(),
+            scan_time: 0.0,
+            rewritten_body: None,
+            fallback: Some(reason),
+        }
+    }
+
+    /// Whether the reply has been synthesized by the client rather than sent by the server
+    pub fn is_fallback(&self) -> bool {
+        self.fallback.is_some()
+    }
 }
 
 /// Symbol structure
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Symbol {
     #[serde(default)]
     pub name: String,
@@ -67,7 +100,7 @@ pub struct Symbol {
 }
 
 /// Milter actions block
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Milter {
     #[serde(default)]
     pub add_headers: HashMap<String, MailHeader>,
@@ -76,7 +109,7 @@ pub struct Milter {
 }
 
 /// Milter header action
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MailHeader {
     #[serde(default)]
     pub value: String,
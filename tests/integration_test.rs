@@ -2,9 +2,9 @@
 mod tests {
     use rspamd_client::config::{Config, EnvelopeData};
     #[cfg(feature = "async")]
-    use rspamd_client::scan_async;
+    use rspamd_client::{ping_async, scan_async};
     #[cfg(feature = "sync")]
-    use rspamd_client::scan_sync;
+    use rspamd_client::{ping_sync, scan_sync};
 
     #[cfg(feature = "sync")]
     #[test]
@@ -78,4 +78,35 @@ mod tests {
         let _response = scan_async(&config, "", envelope).await.unwrap();
         // Test passes if no error is returned
     }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn test_sync_ping() {
+        let config = Config::builder()
+            .base_url("http://localhost:11333".to_string())
+            .build();
+        ping_sync(&config).unwrap();
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_async_ping() {
+        let config = Config::builder()
+            .base_url("http://localhost:11333".to_string())
+            .encryption_key("k4nz984k36xmcynm1hr9kdbn6jhcxf4ggbrb1quay7f88rpm9kay".to_string())
+            .build();
+        ping_async(&config).await.unwrap();
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_async_controller_maps() {
+        let config = Config::builder()
+            .base_url("http://localhost:11334".to_string())
+            .build();
+        let controller = rspamd_client::Controller::new(&config);
+        for map in controller.maps().await.unwrap() {
+            controller.get_map(map.id).await.unwrap();
+        }
+    }
 }